@@ -1,7 +1,11 @@
 use clap::Parser;
+use std::path::PathBuf;
 
 pub mod protocol;
 use protocol::Protocol;
+use protocol::config::Config;
+use protocol::packets::Encoding;
+use protocol::congestion::Algorithm;
 
 /// Client for custom protocol
 #[derive(Parser, Debug)]
@@ -17,7 +21,23 @@ struct Args {
 
     // The size of the message to send
     #[arg(short, long, default_value_t = 50000)]
-    size : u64
+    size : u64,
+
+    /// Use the fixed size header encoding, easier to read when debugging
+    #[arg(long)]
+    fixed_header : bool,
+
+    /// Record the traffic to a pcapng file, to be opened with standard tools
+    #[arg(long)]
+    trace : Option<PathBuf>,
+
+    /// Congestion control algorithm, newreno, cubic or bbr
+    #[arg(long, default_value = "newreno")]
+    congestion : Algorithm,
+
+    /// Send at most this many bytes per second, paced from the congestion control otherwise
+    #[arg(long)]
+    pacing_rate : Option<u64>
 }
 
 fn main() -> Result<(), std::io::Error>{
@@ -25,14 +45,18 @@ fn main() -> Result<(), std::io::Error>{
 
     println!("Hello from client");
 
-    let mut client = Protocol::new(&args.addr)?;
+    let mut config = Config::default();
+    if args.fixed_header{
+        config.encoding = Encoding::Fixed;
+    }
+    config.trace = args.trace;
+    config.congestion = args.congestion;
+    config.pacing_rate = args.pacing_rate;
+    let mut client = Protocol::with_config(&args.addr, config)?;
 
     let mut connection = client.connect(args.peer.clone())?;
 
-    let mut msg = vec![];
-    for _ in 0..args.size {
-        msg.push(b'a');
-    }
+    let msg = vec![b'a'; args.size as usize];
 
     // first let's be polite and greet the server
     connection.send("Hey".as_bytes().to_vec())?;
@@ -52,6 +76,9 @@ fn main() -> Result<(), std::io::Error>{
         println!("Server said : {}", String::from_utf8(data).unwrap());
     }
 
+    let stats = client.stats();
+    println!("Sent {} bytes in {} datagrams", stats.get_bytes_sent(), stats.get_datagrams_sent());
+
     // stop the client
     client.stop();
     
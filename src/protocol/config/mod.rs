@@ -0,0 +1,33 @@
+use std::path::PathBuf;
+
+use crate::protocol::packets::Encoding;
+use crate::protocol::congestion::Algorithm;
+use crate::protocol::connection::RECEIVE_BUFFER;
+
+/**
+ * Settings of a protocol, shared by all its connections
+ */
+#[derive(Debug, Clone)]
+pub struct Config{
+    // layout of the packet headers we send, we always accept both
+    pub encoding : Encoding,
+    // pcapng file recording every datagram sent and received, none by default
+    pub trace : Option<PathBuf>,
+    // offer the timestamp option in the handshake, used if the peer agrees
+    pub timestamps : bool,
+    // congestion control of the connections, each one may pick another with Connection::with_congestion
+    pub congestion : Algorithm,
+    // most bytes per second sent by each connection, none lets the congestion control pace them
+    pub pacing_rate : Option<u64>,
+    // send the data segments ECN capable and read the marks of the datagrams received, on Linux only
+    pub ecn : bool,
+    // bytes each connection holds for the application before the peer must wait, advertised in every ack
+    pub receive_buffer : u64
+}
+
+impl Default for Config{
+    fn default() -> Self{
+        Config{encoding : Encoding::default(), trace : None, timestamps : true, congestion : Algorithm::default(), pacing_rate : None,
+            ecn : true, receive_buffer : RECEIVE_BUFFER}
+    }
+}
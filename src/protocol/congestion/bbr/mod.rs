@@ -0,0 +1,406 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use rand::random;
+
+use crate::protocol::connection::MAX_SIZE;
+use crate::protocol::congestion::{CongestionController, RateSample, INITIAL_WINDOW, LOSS_WINDOW};
+use crate::protocol::rtt::RttEstimator;
+
+// gain doubling the sending rate every round trip during startup, 2/ln(2)
+const HIGH_GAIN : f64 = 2.885;
+// window gain once the pipe is full, so that delayed and stretched acks don't stall the flow
+const CWND_GAIN : f64 = 2.0;
+// pacing gains cycled through once the pipe is full, probing for more bandwidth then draining the queue it built
+const PACING_GAIN_CYCLE : [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+// round trips over which the highest delivery rate is kept as the bottleneck bandwidth
+const BTL_BW_ROUNDS : u64 = 10;
+// time after which the min RTT is measured again
+const MIN_RTT_LIFETIME : Duration = Duration::from_secs(10);
+// time spent with a tiny window to measure the min RTT
+const PROBE_RTT_DURATION : Duration = Duration::from_millis(200);
+// smallest window, enough for delayed acks to keep coming
+const MIN_PIPE_CWND : u64 = 4 * MAX_SIZE as u64;
+// startup goes on while the bandwidth grows by that much every few round trips
+const FULL_BW_GROWTH : f64 = 1.25;
+const FULL_BW_ROUNDS : u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode{
+    // ramp up exponentially until the delivery rate stops growing
+    Startup,
+    // empty the queue built during startup
+    Drain,
+    // send at the bottleneck rate, probing for more now and then
+    ProbeBw,
+    // shrink the window to see the round trip time without queues
+    ProbeRtt
+}
+
+/**
+ * Model based congestion control: the window and the pacing rate follow the bottleneck
+ * bandwidth and the min RTT measured on the deliveries, so that random losses
+ * don't shrink them (BBR, as in draft-cardwell-iccrg-bbr-congestion-control)
+ */
+#[derive(Debug, Clone)]
+pub struct Bbr{
+    mode : Mode,
+    // congestion window
+    cwnd : u64,
+    // highest delivery rate of each of the last rounds, in bytes per second
+    bw_samples : VecDeque<(u64, f64)>,
+    // estimated bottleneck bandwidth, the highest of those
+    btl_bw : f64,
+    // lowest round trip time seen lately, none until the first sample
+    min_rtt : Option<Duration>,
+    // when the min RTT was seen
+    min_rtt_stamp : Instant,
+    // round trips counted on the deliveries
+    round_count : u64,
+    // delivered count that ends the current round trip
+    next_round_delivered : u64,
+    // bandwidth when startup last saw it grow, and round trips since then
+    full_bw : f64,
+    full_bw_rounds : u32,
+    // startup found the bottleneck bandwidth
+    filled_pipe : bool,
+    pacing_gain : f64,
+    cwnd_gain : f64,
+    // position in PACING_GAIN_CYCLE, and when it was reached
+    cycle_index : usize,
+    cycle_stamp : Instant,
+    // end of probe RTT, once the window is small enough, and the round trip it must outlast
+    probe_rtt_done : Option<(Instant, u64)>,
+    // window before probe RTT, restored afterwards
+    prior_cwnd : u64
+}
+
+impl Default for Bbr{
+    fn default() -> Self{
+        Bbr::new()
+    }
+}
+
+impl Bbr{
+    pub fn new() -> Bbr{
+        Bbr{mode : Mode::Startup, cwnd : INITIAL_WINDOW, bw_samples : VecDeque::new(), btl_bw : 0.0,
+            min_rtt : None, min_rtt_stamp : Instant::now(), round_count : 0, next_round_delivered : 0,
+            full_bw : 0.0, full_bw_rounds : 0, filled_pipe : false, pacing_gain : HIGH_GAIN, cwnd_gain : HIGH_GAIN,
+            cycle_index : 0, cycle_stamp : Instant::now(), probe_rtt_done : None, prior_cwnd : INITIAL_WINDOW}
+    }
+
+    /**
+     * Estimated bandwidth delay product times gain, the initial window while not measured
+     */
+    fn bdp(&self, gain : f64) -> u64{
+        match self.min_rtt{
+            Some(min_rtt) if self.btl_bw > 0.0 => u64::max((gain * self.btl_bw * min_rtt.as_secs_f64()) as u64, MIN_PIPE_CWND),
+            _ => (gain * INITIAL_WINDOW as f64) as u64
+        }
+    }
+
+    /**
+     * Keep the highest delivery rate of the last rounds, a rate limited by the application
+     * only counts if it is higher than the estimation anyway
+     */
+    fn update_btl_bw(&mut self, sample : &RateSample){
+        let Some(rate) = sample.rate() else{
+            return;
+        };
+        if sample.app_limited && rate < self.btl_bw{
+            return;
+        }
+        while self.bw_samples.front().is_some_and(|(round, _)| round + BTL_BW_ROUNDS <= self.round_count){
+            self.bw_samples.pop_front();
+        }
+        match self.bw_samples.back_mut(){
+            Some((round, bw)) if *round == self.round_count => *bw = f64::max(*bw, rate),
+            _ => self.bw_samples.push_back((self.round_count, rate))
+        }
+        self.btl_bw = self.bw_samples.iter().map(|(_, bw)| *bw).fold(0.0, f64::max);
+    }
+
+    /**
+     * The pipe is full once the bandwidth stops growing for a few round trips
+     */
+    fn check_full_pipe(&mut self, sample : &RateSample){
+        if self.filled_pipe || sample.app_limited{
+            return;
+        }
+        if self.btl_bw >= self.full_bw * FULL_BW_GROWTH{
+            self.full_bw = self.btl_bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        if self.full_bw_rounds >= FULL_BW_ROUNDS{
+            self.filled_pipe = true;
+        }
+    }
+
+    /**
+     * Keep the lowest round trip time, measure it again in probe RTT when it gets too old
+     */
+    fn update_min_rtt(&mut self, rtt : Duration, now : Instant){
+        let expired = now.duration_since(self.min_rtt_stamp) > MIN_RTT_LIFETIME;
+        if expired || self.min_rtt.is_none_or(|min_rtt| rtt <= min_rtt){
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = now;
+        }
+        if expired && self.mode != Mode::ProbeRtt{
+            self.mode = Mode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.cwnd_gain = 1.0;
+            self.prior_cwnd = self.cwnd;
+            self.probe_rtt_done = None;
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now : Instant){
+        self.mode = Mode::ProbeBw;
+        self.cwnd_gain = CWND_GAIN;
+        // start anywhere but in the draining phase
+        let index = random::<usize>() % (PACING_GAIN_CYCLE.len() - 1);
+        self.cycle_index = if index == 0 { 0 } else { index + 1 };
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        self.cycle_stamp = now;
+    }
+
+    fn update_mode(&mut self, sample : &RateSample, now : Instant){
+        let min_rtt = self.min_rtt.unwrap_or_default();
+        match self.mode{
+            Mode::Startup if self.filled_pipe => {
+                self.mode = Mode::Drain;
+                self.pacing_gain = 1.0 / HIGH_GAIN;
+                self.cwnd_gain = HIGH_GAIN;
+            },
+            Mode::Drain if sample.in_flight <= self.bdp(1.0) => self.enter_probe_bw(now),
+            Mode::ProbeBw => {
+                let elapsed = now.duration_since(self.cycle_stamp) > min_rtt;
+                // the draining phase ends early once the queue is gone
+                let drained = self.pacing_gain < 1.0 && sample.in_flight <= self.bdp(1.0);
+                if elapsed || drained{
+                    self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+                    self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+                    self.cycle_stamp = now;
+                }
+            },
+            Mode::ProbeRtt => match self.probe_rtt_done{
+                None if sample.in_flight <= MIN_PIPE_CWND => {
+                    self.probe_rtt_done = Some((now + PROBE_RTT_DURATION, self.round_count));
+                },
+                Some((done, round)) if now >= done && self.round_count > round => {
+                    self.min_rtt_stamp = now;
+                    self.cwnd = u64::max(self.cwnd, self.prior_cwnd);
+                    if self.filled_pipe{
+                        self.enter_probe_bw(now);
+                    }else{
+                        self.mode = Mode::Startup;
+                        self.pacing_gain = HIGH_GAIN;
+                        self.cwnd_gain = HIGH_GAIN;
+                    }
+                },
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /**
+     * Grow the window with the deliveries up to the target, it only grows without bound until the pipe is full
+     */
+    fn update_cwnd(&mut self, sample : &RateSample){
+        if self.mode == Mode::ProbeRtt{
+            self.cwnd = u64::min(self.cwnd, MIN_PIPE_CWND);
+            return;
+        }
+        let target = self.bdp(self.cwnd_gain);
+        if self.filled_pipe{
+            self.cwnd = u64::min(self.cwnd + sample.acked, target);
+        }else if self.cwnd < target || sample.total_delivered < INITIAL_WINDOW{
+            self.cwnd += sample.acked;
+        }
+        self.cwnd = u64::max(self.cwnd, MIN_PIPE_CWND);
+    }
+}
+
+impl CongestionController for Bbr{
+    fn on_ack(&mut self, _acked : u64, _rtt : &RttEstimator){
+        // everything is driven by the delivery rate samples
+    }
+
+    fn on_loss(&mut self, _in_flight : u64){
+        // a random loss tells nothing about the bottleneck, the window keeps following the model
+    }
+
+    fn on_rto(&mut self, _in_flight : u64){
+        // the deliveries grow it back to the target quickly
+        self.cwnd = LOSS_WINDOW;
+    }
+
+    fn cwnd(&self) -> u64{
+        self.cwnd
+    }
+
+    fn on_delivery(&mut self, sample : &RateSample){
+        let now = Instant::now();
+        // a round trip ends when a segment sent after it started is delivered
+        let round_start = sample.prior_delivered >= self.next_round_delivered;
+        if round_start{
+            self.next_round_delivered = sample.total_delivered;
+            self.round_count += 1;
+        }
+        self.update_btl_bw(sample);
+        if round_start{
+            self.check_full_pipe(sample);
+        }
+        self.update_min_rtt(sample.rtt, now);
+        self.update_mode(sample, now);
+        self.update_cwnd(sample);
+    }
+
+    fn pacing_rate(&self) -> Option<f64>{
+        Some(self.pacing_gain * self.btl_bw).filter(|rate| *rate > 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const RTT : Duration = Duration::from_millis(10);
+
+    /**
+     * Bytes delivered so far on a path with a fixed round trip time
+     */
+    #[derive(Default)]
+    struct Path{
+        delivered : u64
+    }
+
+    impl Path{
+        /**
+         * A round trip delivering at rate bytes per second, acked at once
+         */
+        fn round(&mut self, bbr : &mut Bbr, rate : f64, in_flight : u64){
+            let acked = (rate * RTT.as_secs_f64()) as u64;
+            let prior_delivered = self.delivered;
+            self.delivered += acked;
+            bbr.on_delivery(&RateSample{acked, delivered : acked, interval : Some(RTT), rtt : RTT,
+                prior_delivered, total_delivered : self.delivered, in_flight, app_limited : false});
+        }
+
+        /**
+         * A round trip with the window full
+         */
+        fn busy_round(&mut self, bbr : &mut Bbr, rate : f64){
+            let in_flight = bbr.cwnd();
+            self.round(bbr, rate, in_flight);
+        }
+    }
+
+    /**
+     * Through startup and drain, at a bottleneck of rate bytes per second
+     */
+    fn probing_bandwidth(path : &mut Path, rate : f64) -> Bbr{
+        let mut bbr = Bbr::new();
+        for _ in 0..4{
+            path.busy_round(&mut bbr, rate);
+        }
+        path.round(&mut bbr, rate, 0);
+        assert_eq!(bbr.mode, Mode::ProbeBw);
+        bbr
+    }
+
+    fn close(a : f64, b : f64) -> bool{
+        (a - b).abs() <= 1e-6 * b.abs()
+    }
+
+    #[test]
+    fn startup_lasts_while_the_bandwidth_grows(){
+        let mut path = Path::default();
+        let mut bbr = Bbr::new();
+        let mut rate = 1e5;
+        for _ in 0..10{
+            path.busy_round(&mut bbr, rate);
+            rate *= 2.0;
+        }
+        assert_eq!(bbr.mode, Mode::Startup);
+        assert!(close(bbr.pacing_rate().unwrap(), HIGH_GAIN * rate / 2.0));
+    }
+
+    #[test]
+    fn startup_drains_then_probes_the_bandwidth(){
+        let mut path = Path::default();
+        let mut bbr = Bbr::new();
+        // the bandwidth stops growing, startup ends after three round trips
+        for _ in 0..3{
+            path.busy_round(&mut bbr, 1e6);
+            assert_eq!(bbr.mode, Mode::Startup);
+        }
+        path.busy_round(&mut bbr, 1e6);
+        assert_eq!(bbr.mode, Mode::Drain);
+        assert!(close(bbr.pacing_gain, 1.0 / HIGH_GAIN));
+        // until the queue is gone
+        let bdp = bbr.bdp(1.0);
+        path.round(&mut bbr, 1e6, 2 * bdp);
+        assert_eq!(bbr.mode, Mode::Drain);
+        path.round(&mut bbr, 1e6, bdp);
+        assert_eq!(bbr.mode, Mode::ProbeBw);
+        assert_eq!(bbr.cwnd_gain, CWND_GAIN);
+        // never starting with the draining phase
+        assert_ne!(bbr.cycle_index, 1);
+        assert_eq!(bbr.pacing_gain, PACING_GAIN_CYCLE[bbr.cycle_index]);
+    }
+
+    #[test]
+    fn the_window_and_the_pacing_rate_follow_the_bandwidth_delay_product(){
+        let mut path = Path::default();
+        let mut bbr = probing_bandwidth(&mut path, 1e7);
+        for _ in 0..3{
+            path.busy_round(&mut bbr, 1e7);
+        }
+        assert!(close(bbr.btl_bw, 1e7));
+        assert_eq!(bbr.min_rtt, Some(RTT));
+        // twice the bandwidth delay product, 2 * 10 MB/s * 10 ms
+        assert!(bbr.cwnd().abs_diff(200_000) <= 1);
+        assert!(close(bbr.pacing_rate().unwrap(), bbr.pacing_gain * 1e7));
+        // the bandwidth halves, the highest rate is kept for ten round trips
+        for _ in 0..9{
+            path.busy_round(&mut bbr, 5e6);
+        }
+        assert!(close(bbr.btl_bw, 1e7));
+        path.busy_round(&mut bbr, 5e6);
+        assert!(close(bbr.btl_bw, 5e6));
+        assert!(bbr.cwnd().abs_diff(100_000) <= 1);
+    }
+
+    #[test]
+    fn probe_rtt_shrinks_the_window_then_restores_it(){
+        let mut path = Path::default();
+        let mut bbr = probing_bandwidth(&mut path, 1e7);
+        path.busy_round(&mut bbr, 1e7);
+        let cwnd = bbr.cwnd();
+        assert!(cwnd > MIN_PIPE_CWND);
+
+        // the min RTT wasn't seen again for too long
+        bbr.min_rtt_stamp = Instant::now() - MIN_RTT_LIFETIME - Duration::from_millis(1);
+        path.round(&mut bbr, 1e7, cwnd);
+        assert_eq!(bbr.mode, Mode::ProbeRtt);
+        assert_eq!(bbr.cwnd(), MIN_PIPE_CWND);
+        assert_eq!(bbr.prior_cwnd, cwnd);
+        assert_eq!(bbr.probe_rtt_done, None);
+
+        // the window drained, it stays small for a while and at least a round trip
+        path.round(&mut bbr, 1e7, MIN_PIPE_CWND);
+        let (done, round) = bbr.probe_rtt_done.unwrap();
+        assert!(done > Instant::now());
+        assert_eq!(round, bbr.round_count);
+        path.round(&mut bbr, 1e7, MIN_PIPE_CWND);
+        assert_eq!(bbr.mode, Mode::ProbeRtt);
+
+        bbr.probe_rtt_done = Some((Instant::now(), round));
+        path.round(&mut bbr, 1e7, MIN_PIPE_CWND);
+        assert_eq!(bbr.mode, Mode::ProbeBw);
+        assert_eq!(bbr.cwnd(), cwnd);
+    }
+}
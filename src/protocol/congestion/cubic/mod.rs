@@ -0,0 +1,199 @@
+use std::time::Instant;
+
+use crate::protocol::connection::MAX_SIZE;
+use crate::protocol::congestion::{CongestionController, INITIAL_WINDOW, LOSS_WINDOW, MIN_WINDOW};
+use crate::protocol::rtt::RttEstimator;
+
+// how fast the window grows back, in segments per second cubed
+const C : f64 = 0.4;
+// window kept on loss
+const BETA : f64 = 0.7;
+
+/**
+ * Window following a cubic function of the time since the last loss, flat around
+ * the window at which the loss happened and growing fast away from it (RFC 9438).
+ * Windows are counted in segments, as in the RFC
+ */
+#[derive(Debug, Clone)]
+pub struct Cubic{
+    // congestion window
+    cwnd : f64,
+    // slow start threshold, unbounded until the first loss
+    ssthresh : f64,
+    // window just before the last reduction
+    w_max : f64,
+    // time the window takes to grow back to w_max, in seconds
+    k : f64,
+    // window a NewReno flow would have, the window never grows slower than that
+    w_est : f64,
+    // start of the current congestion avoidance stage, none in slow start or right after a loss
+    epoch_start : Option<Instant>
+}
+
+impl Default for Cubic{
+    fn default() -> Self{
+        Cubic::new()
+    }
+}
+
+impl Cubic{
+    pub fn new() -> Cubic{
+        Cubic{cwnd : segments(INITIAL_WINDOW), ssthresh : f64::INFINITY, w_max : 0.0, k : 0.0, w_est : 0.0, epoch_start : None}
+    }
+
+    /**
+     * Window of the cubic function, t seconds into the epoch
+     */
+    fn w_cubic(&self, t : f64) -> f64{
+        C * (t - self.k).powi(3) + self.w_max
+    }
+
+    /**
+     * Remember the window at which the loss happened, lower when the window was
+     * already shrinking so that a new flow gets its share sooner (fast convergence)
+     */
+    fn reduce(&mut self){
+        self.epoch_start = None;
+        self.w_max = if self.cwnd < self.w_max{ self.cwnd * (1.0 + BETA) / 2.0 } else { self.cwnd };
+        self.ssthresh = f64::max(self.cwnd * BETA, segments(MIN_WINDOW));
+    }
+}
+
+/**
+ * A number of bytes as a number of segments
+ */
+fn segments(bytes : u64) -> f64{
+    bytes as f64 / MAX_SIZE as f64
+}
+
+impl CongestionController for Cubic{
+    fn on_ack(&mut self, acked : u64, rtt : &RttEstimator){
+        let acked = segments(acked);
+        if self.cwnd < self.ssthresh{
+            // slow start, with appropriate byte counting as in NewReno
+            self.cwnd += f64::min(acked, 2.0);
+            return;
+        }
+        let now = Instant::now();
+        let epoch_start = *self.epoch_start.get_or_insert_with(|| {
+            if self.cwnd < self.w_max{
+                self.k = ((self.w_max - self.cwnd) / C).cbrt();
+            }else{
+                self.k = 0.0;
+                self.w_max = self.cwnd;
+            }
+            self.w_est = self.cwnd;
+            now
+        });
+        let t = now.duration_since(epoch_start).as_secs_f64();
+        let rtt = rtt.get_srtt().unwrap_or_default().as_secs_f64();
+
+        // NewReno grows by one segment per round trip, the window is
+        // shared with NewReno flows by growing as fast when they gain more
+        let alpha = if self.w_est < self.w_max{ 3.0 * (1.0 - BETA) / (1.0 + BETA) } else { 1.0 };
+        self.w_est += alpha * acked / self.cwnd;
+        if self.w_cubic(t) < self.w_est{
+            // NewReno friendly region
+            self.cwnd = self.w_est;
+            return;
+        }
+        // the window aims at its value one round trip ahead, without more than a 50% increase
+        let target = self.w_cubic(t + rtt).clamp(self.cwnd, 1.5 * self.cwnd);
+        self.cwnd += (target - self.cwnd) / self.cwnd * acked;
+    }
+
+    fn on_loss(&mut self, _in_flight : u64){
+        // right after a timeout, the window was already reduced
+        if self.cwnd > segments(LOSS_WINDOW){
+            self.reduce();
+            self.cwnd = f64::min(self.cwnd, self.ssthresh);
+        }
+    }
+
+    fn on_rto(&mut self, _in_flight : u64){
+        // timeouts in a row don't lower the threshold further
+        if self.cwnd > segments(LOSS_WINDOW){
+            self.reduce();
+        }
+        self.cwnd = segments(LOSS_WINDOW);
+    }
+
+    fn cwnd(&self) -> u64{
+        (self.cwnd * MAX_SIZE as f64) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Duration;
+
+    use super::*;
+
+    const SEGMENT : u64 = MAX_SIZE as u64;
+
+    /**
+     * Out of slow start after a loss at a window of w_max segments
+     */
+    fn after_loss(w_max : f64) -> Cubic{
+        let mut cubic = Cubic::new();
+        cubic.cwnd = w_max;
+        cubic.on_loss(0);
+        cubic
+    }
+
+    #[test]
+    fn the_window_grows_back_to_w_max_in_k_seconds(){
+        let mut cubic = after_loss(10.0);
+        assert_eq!(cubic.w_max, 10.0);
+        assert_eq!(cubic.ssthresh, 7.0);
+        assert_eq!(cubic.cwnd, 7.0);
+        cubic.on_ack(SEGMENT, &RttEstimator::new());
+        assert!((cubic.k - (3.0 / C).cbrt()).abs() < 1e-9);
+        assert!((cubic.w_cubic(cubic.k) - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn the_window_grows_as_newreno_would_right_after_a_loss(){
+        let mut cubic = after_loss(10.0);
+        cubic.on_ack(SEGMENT, &RttEstimator::new());
+        // the cubic function is still flat at the reduced window
+        let alpha = 3.0 * (1.0 - BETA) / (1.0 + BETA);
+        assert!((cubic.w_est - (7.0 + alpha / 7.0)).abs() < 1e-9);
+        assert_eq!(cubic.cwnd, cubic.w_est);
+    }
+
+    #[test]
+    fn the_window_follows_the_cubic_function_at_most_50_percent_ahead(){
+        let mut cubic = after_loss(10.0);
+        cubic.on_ack(SEGMENT, &RttEstimator::new());
+        // far into the epoch, the cubic function is way above the window
+        cubic.epoch_start = Some(Instant::now() - Duration::from_secs(10));
+        let cwnd = cubic.cwnd;
+        cubic.on_ack(SEGMENT, &RttEstimator::new());
+        assert!((cubic.cwnd - (cwnd + 0.5)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_loss_before_reaching_w_max_lowers_it_further(){
+        let mut cubic = after_loss(12.0);
+        cubic.cwnd = 10.0;
+        cubic.on_loss(0);
+        assert_eq!(cubic.w_max, 10.0 * (1.0 + BETA) / 2.0);
+        assert_eq!(cubic.ssthresh, 7.0);
+        assert_eq!(cubic.cwnd, 7.0);
+    }
+
+    #[test]
+    fn timeouts_restart_from_a_single_segment(){
+        let mut cubic = after_loss(10.0);
+        cubic.on_rto(0);
+        assert_eq!(cubic.cwnd(), LOSS_WINDOW);
+        // a timeout in a row doesn't lower the threshold further
+        let ssthresh = cubic.ssthresh;
+        cubic.on_rto(0);
+        assert_eq!(cubic.ssthresh, ssthresh);
+        // nor does the loss detected right after
+        cubic.on_loss(0);
+        assert_eq!(cubic.cwnd(), LOSS_WINDOW);
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::protocol::connection::MAX_SIZE;
+use crate::protocol::rtt::RttEstimator;
+
+pub mod newreno;
+pub mod cubic;
+pub mod bbr;
+
+use newreno::NewReno;
+use cubic::Cubic;
+use bbr::Bbr;
+
+// window of a new connection, the fixed window used before congestion control
+pub const INITIAL_WINDOW : u64 = 4 * MAX_SIZE as u64;
+// smallest window after a loss, so that dup acks can still reveal the next one
+pub const MIN_WINDOW : u64 = 2 * MAX_SIZE as u64;
+// window after a retransmission timeout, a single segment
+pub const LOSS_WINDOW : u64 = MAX_SIZE as u64;
+
+/**
+ * Delivery rate measured when an ack delivers some segments,
+ * over the interval since the segment sent last among them left
+ */
+#[derive(Debug, Clone)]
+pub struct RateSample{
+    // bytes delivered by this ack
+    pub acked : u64,
+    // bytes delivered during the interval
+    pub delivered : u64,
+    // time over which they were delivered, none when too short to measure a rate
+    pub interval : Option<Duration>,
+    // round trip time of the segment
+    pub rtt : Duration,
+    // bytes delivered when the segment was sent
+    pub prior_delivered : u64,
+    // bytes delivered since the connection started
+    pub total_delivered : u64,
+    // bytes still in flight that the peer doesn't hold
+    pub in_flight : u64,
+    // the application didn't fill the window, the path may allow a higher rate
+    pub app_limited : bool
+}
+
+impl RateSample{
+    /**
+     * Delivery rate, in bytes per second
+     */
+    pub fn rate(&self) -> Option<f64>{
+        self.interval.map(|interval| self.delivered as f64 / interval.as_secs_f64())
+    }
+}
+
+/**
+ * Decides how many bytes a connection may have in flight, from the acks,
+ * the losses and the retransmission timeouts it sees
+ */
+pub trait CongestionController : Debug + Send{
+    /**
+     * Some bytes were acked for the first time, outside of loss recovery
+     * and while the application fills the window
+     */
+    fn on_ack(&mut self, acked : u64, rtt : &RttEstimator);
+
+    /**
+     * A loss was detected, called once per window of data
+     */
+    fn on_loss(&mut self, in_flight : u64);
+
+    /**
+     * The retransmission timer expired
+     */
+    fn on_rto(&mut self, in_flight : u64);
+
+    /**
+     * The peer received segments marked congestion experienced, called once per window of data.
+     * Routers mark instead of dropping, so it is a loss that didn't happen by default
+     */
+    fn on_ce(&mut self, in_flight : u64){
+        self.on_loss(in_flight);
+    }
+
+    /**
+     * Current congestion window, in bytes
+     */
+    fn cwnd(&self) -> u64;
+
+    /**
+     * An ack delivered some segments, called on every such ack, during loss recovery too
+     */
+    fn on_delivery(&mut self, _sample : &RateSample){}
+
+    /**
+     * Rate at which the segments should leave, in bytes per second, none when not paced
+     */
+    fn pacing_rate(&self) -> Option<f64>{
+        None
+    }
+}
+
+/**
+ * Congestion control algorithms a connection can use
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm{
+    #[default]
+    NewReno,
+    Cubic,
+    Bbr
+}
+
+impl Algorithm{
+    /**
+     * A new controller running this algorithm, for a single connection
+     */
+    pub fn controller(self) -> Box<dyn CongestionController>{
+        match self{
+            Algorithm::NewReno => Box::new(NewReno::new()),
+            Algorithm::Cubic => Box::new(Cubic::new()),
+            Algorithm::Bbr => Box::new(Bbr::new())
+        }
+    }
+}
+
+impl FromStr for Algorithm{
+    type Err = String;
+
+    fn from_str(name : &str) -> Result<Algorithm, String>{
+        match name.to_ascii_lowercase().as_str(){
+            "newreno" => Ok(Algorithm::NewReno),
+            "cubic" => Ok(Algorithm::Cubic),
+            "bbr" => Ok(Algorithm::Bbr),
+            _ => Err(format!("unknown congestion control algorithm {}, expected newreno, cubic or bbr", name))
+        }
+    }
+}
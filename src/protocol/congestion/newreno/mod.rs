@@ -0,0 +1,127 @@
+use crate::protocol::connection::MAX_SIZE;
+use crate::protocol::congestion::{CongestionController, INITIAL_WINDOW, LOSS_WINDOW, MIN_WINDOW};
+use crate::protocol::rtt::RttEstimator;
+
+/**
+ * Window growing exponentially up to the slow start threshold then by one segment
+ * per round trip, halved on loss (RFC 5681 and RFC 6582)
+ */
+#[derive(Debug, Clone)]
+pub struct NewReno{
+    // congestion window
+    cwnd : u64,
+    // slow start threshold, unbounded until the first loss
+    ssthresh : u64,
+    // bytes acked since the window last grew, during congestion avoidance
+    bytes_acked : u64
+}
+
+impl Default for NewReno{
+    fn default() -> Self{
+        NewReno::new()
+    }
+}
+
+impl NewReno{
+    pub fn new() -> NewReno{
+        NewReno{cwnd : INITIAL_WINDOW, ssthresh : u64::MAX, bytes_acked : 0}
+    }
+}
+
+impl CongestionController for NewReno{
+    fn on_ack(&mut self, acked : u64, _rtt : &RttEstimator){
+        if self.cwnd < self.ssthresh{
+            // slow start, with appropriate byte counting (RFC 3465)
+            self.cwnd += u64::min(acked, 2 * MAX_SIZE as u64);
+        }else{
+            self.bytes_acked += acked;
+            if self.bytes_acked >= self.cwnd{
+                self.bytes_acked -= self.cwnd;
+                self.cwnd += MAX_SIZE as u64;
+            }
+        }
+    }
+
+    fn on_loss(&mut self, in_flight : u64){
+        self.ssthresh = u64::max(in_flight / 2, MIN_WINDOW);
+        // after a timeout the window is already lower than that
+        self.cwnd = u64::min(self.cwnd, self.ssthresh);
+        self.bytes_acked = 0;
+    }
+
+    fn on_rto(&mut self, in_flight : u64){
+        self.ssthresh = u64::max(in_flight / 2, MIN_WINDOW);
+        self.cwnd = LOSS_WINDOW;
+        self.bytes_acked = 0;
+    }
+
+    fn cwnd(&self) -> u64{
+        self.cwnd
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const SEGMENT : u64 = MAX_SIZE as u64;
+
+    #[test]
+    fn slow_start_grows_by_the_bytes_acked(){
+        let rtt = RttEstimator::new();
+        let mut reno = NewReno::new();
+        reno.on_ack(SEGMENT, &rtt);
+        assert_eq!(reno.cwnd(), INITIAL_WINDOW + SEGMENT);
+        // at most two segments per ack (RFC 3465)
+        reno.on_ack(3 * SEGMENT, &rtt);
+        assert_eq!(reno.cwnd(), INITIAL_WINDOW + 3 * SEGMENT);
+        assert_eq!(reno.ssthresh, u64::MAX);
+    }
+
+    #[test]
+    fn congestion_avoidance_grows_by_a_segment_per_window(){
+        let rtt = RttEstimator::new();
+        let mut reno = NewReno::new();
+        reno.on_loss(8 * SEGMENT);
+        assert_eq!(reno.ssthresh, 4 * SEGMENT);
+        assert_eq!(reno.cwnd(), 4 * SEGMENT);
+        for _ in 0..3{
+            reno.on_ack(SEGMENT, &rtt);
+            assert_eq!(reno.cwnd(), 4 * SEGMENT);
+        }
+        reno.on_ack(SEGMENT, &rtt);
+        assert_eq!(reno.cwnd(), 5 * SEGMENT);
+        assert_eq!(reno.bytes_acked, 0);
+    }
+
+    #[test]
+    fn losses_halve_the_window_down_to_the_minimum(){
+        let rtt = RttEstimator::new();
+        let mut reno = NewReno::new();
+        for _ in 0..6{
+            reno.on_ack(2 * SEGMENT, &rtt);
+        }
+        assert_eq!(reno.cwnd(), 16 * SEGMENT);
+        reno.on_loss(16 * SEGMENT);
+        assert_eq!(reno.ssthresh, 8 * SEGMENT);
+        assert_eq!(reno.cwnd(), 8 * SEGMENT);
+        reno.on_loss(SEGMENT);
+        assert_eq!(reno.ssthresh, MIN_WINDOW);
+        assert_eq!(reno.cwnd(), MIN_WINDOW);
+    }
+
+    #[test]
+    fn timeouts_restart_from_a_single_segment(){
+        let rtt = RttEstimator::new();
+        let mut reno = NewReno::new();
+        reno.on_rto(6 * SEGMENT);
+        assert_eq!(reno.ssthresh, 3 * SEGMENT);
+        assert_eq!(reno.cwnd(), LOSS_WINDOW);
+        // slow start again up to the threshold, then one segment per window
+        reno.on_ack(SEGMENT, &rtt);
+        reno.on_ack(SEGMENT, &rtt);
+        assert_eq!(reno.cwnd(), 3 * SEGMENT);
+        reno.on_ack(SEGMENT, &rtt);
+        assert_eq!(reno.cwnd(), 3 * SEGMENT);
+    }
+}
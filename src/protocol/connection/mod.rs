@@ -1,45 +1,318 @@
-use crate::protocol::packets::Packet;
+use crate::protocol::packets::{Packet, DATAGRAM_HEADER_SIZE};
+use crate::protocol::packets::options::PacketOption;
+use crate::protocol::transport::Transport;
+use crate::protocol::rtt::RttEstimator;
+use crate::protocol::congestion::{CongestionController, RateSample};
+use crate::protocol::congestion::newreno::NewReno;
+use crate::protocol::pacer::Pacer;
+use crate::protocol::ecn::{ce_count_of, Ecn};
+use crate::protocol::timestamps::{is_older, timestamp_of, TimestampClock, Timestamps};
 use core::time;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Error, ErrorKind, Read, Write};
-use std::net::UdpSocket;
 use std::thread::{self, sleep};
-use std::sync::mpsc::{channel, Receiver, RecvError, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 
 pub const MAX_SIZE: usize = 2560;
 pub const MSL: Duration = Duration::from_secs(120);
-pub const RTO : Duration = Duration::from_millis(100);
+// largest datagram built by packing frames, room for a full segment and an ack
+pub const MAX_DATAGRAM : usize = MAX_SIZE + 128;
+// how far beyond the next expected byte out of order segments are kept
+pub const RECEIVE_BUFFER : u64 = 64 * MAX_SIZE as u64;
+// most SACK blocks sent in a single ack
+pub const MAX_SACK_BLOCKS : usize = 4;
+// number of in order segments received before acking them without delay
+pub const ACK_FREQUENCY : usize = 2;
+// longest time an ack waits for more data or for an answer to travel with,
+// kept below the minimal retransmission timeout
+pub const ACK_DELAY : Duration = Duration::from_millis(10);
+// number of retransmission timeouts in a row before giving up on the peer
+pub const MAX_RETRANSMITS : u32 = 10;
+// shortest tail loss probe timeout, so that probes don't fire on the clock granularity
+pub const MIN_PTO : Duration = Duration::from_millis(10);
+// pacing rate derived from the window, twice cwnd/min RTT so that slow start can still double it every round trip
+pub const PACING_GAIN : f64 = 2.0;
+// number of duplicate acks, or of segments sacked above a hole, before it is considered lost
+pub const DUP_THRESHOLD : usize = 3;
+
+/**
+ * Map of <connection id, Route> shared with the receiving thread
+ */
+pub type Connections = Arc<Mutex<HashMap<u32, Route>>>;
+
+/**
+ * What the receiving thread holds for a connection
+ */
+#[derive(Debug, Clone)]
+pub struct Route{
+    // forwards each packet along with the address it came from and the ECN codepoint of its datagram
+    pub sender : Sender<(Packet, String, Ecn)>,
+    // latest ack of the connection, for the thread to send on its behalf
+    pub ack : Arc<Mutex<SharedAck>>
+}
+
+/**
+ * The end of a route a connection is created with
+ */
+#[derive(Debug)]
+pub struct Inbox{
+    pub receiver : Receiver<(Packet, String, Ecn)>,
+    pub ack : Arc<Mutex<SharedAck>>
+}
+
+/**
+ * A new route between the receiving thread and a connection
+ */
+pub fn route() -> (Route, Inbox){
+    let (sender, receiver) = channel();
+    let ack = Arc::new(Mutex::new(SharedAck::default()));
+    (Route{sender, ack : ack.clone()}, Inbox{receiver, ack})
+}
+
+/**
+ * Free space in the receive buffer of the peer past the sequence number it acks, if the packet tells
+ */
+fn window_of(options : &[PacketOption]) -> Option<u32>{
+    options.iter().find_map(|option| match option{
+        PacketOption::Window(window) => Some(*window),
+        _ => None
+    })
+}
+
+/**
+ * Latest ack of a connection, shared with the receiving thread so that the peer gets
+ * the delayed acks and the answers to its keepalive probes while the application is busy elsewhere
+ */
+#[derive(Debug, Default)]
+pub struct SharedAck{
+    // the ack without its timestamp, none until the connection has one
+    ack : Option<Packet>,
+    // stamps the ack when it leaves, if both hosts agreed on timestamps
+    timestamps : Option<Timestamps>,
+    // address of the peer
+    addr : String,
+    // time at which the ack must leave, when it is delayed
+    deadline : Option<Instant>
+}
+
+impl SharedAck{
+    fn update(&mut self, ack : Packet, timestamps : Option<Timestamps>, addr : String, deadline : Option<Instant>){
+        *self = SharedAck{ack : Some(ack), timestamps, addr, deadline};
+    }
+
+    /**
+     * The ack, stamped now, along with the address to send it to
+     */
+    fn packet(&self) -> Option<(Packet, String)>{
+        let ack = self.ack.clone()?;
+        let ack = match &self.timestamps{
+            Some(timestamps) => ack.with_option(timestamps.option()),
+            None => ack
+        };
+        Some((ack, self.addr.clone()))
+    }
+
+    /**
+     * The ack to send back if the packet is a keepalive probe or an ack overtaken by data
+     */
+    pub fn answer(&self, packet : &Packet) -> Option<(Packet, String)>{
+        let acked = self.ack.as_ref()?.get_acked();
+        if !packet.is_pure_ack() || packet.get_sequence() >= acked{
+            return None;
+        }
+        self.packet()
+    }
+
+    /**
+     * The delayed ack if it can't wait any longer, it is no longer owed then
+     */
+    pub fn due(&mut self, now : Instant) -> Option<(Packet, String)>{
+        if self.deadline.is_none_or(|deadline| now < deadline){
+            return None;
+        }
+        self.deadline = None;
+        self.packet()
+    }
+
+    /**
+     * The ack left, it is no longer owed
+     */
+    fn sent(&mut self){
+        self.deadline = None;
+    }
+
+    /**
+     * Whether the delayed ack is still owed
+     */
+    fn is_delayed(&self) -> bool{
+        self.deadline.is_some()
+    }
+}
+
+/**
+ * Keepalive settings, probes are sent once nothing was heard from the peer for `idle`,
+ * then every `interval`, and the connection is torn down after `count` unanswered probes
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive{
+    pub idle : Duration,
+    pub interval : Duration,
+    pub count : u32
+}
+
+/**
+ * Latest sent segment known to be delivered, segments sent before it
+ * are lost once they are late by more than the reordering window (RACK)
+ */
+#[derive(Debug)]
+struct Rack{
+    // when the segment was (re)transmitted
+    sent_at : Instant,
+    // sequence number following the segment
+    end : u64,
+    // round trip time of the segment
+    rtt : Duration
+}
+
+impl Rack{
+    /**
+     * Whether this segment was sent after the segment sent at sent_at and ending at end
+     */
+    fn sent_after(&self, sent_at : Instant, end : u64) -> bool{
+        self.sent_at > sent_at || (self.sent_at == sent_at && self.end > end)
+    }
+}
+
+/**
+ * A segment sent but not acknowledged yet
+ */
+#[derive(Debug, Clone)]
+struct Segment{
+    // number of bytes in the segment
+    len : u64,
+    // last time the segment was (re)transmitted
+    sent_at : Instant,
+    // the peer told us it holds the segment, it doesn't need to be sent again
+    sacked : bool,
+    // the segment was already sent again, don't count on the SACK blocks to retransmit it twice
+    retransmitted : bool,
+    // the segment is considered lost and waits to be sent again, as soon as the window allows
+    lost : bool,
+    // bytes delivered when the segment was sent, to measure the delivery rate once it is delivered
+    delivered : u64,
+    // time of the latest delivery when the segment was sent
+    delivered_at : Instant,
+    // send time of the first segment of the interval the delivery rate is measured on
+    first_sent_at : Instant,
+    // the application had nothing more to send, the delivery rate may be lower than what the path allows
+    app_limited : bool
+}
 
 #[derive(Debug)]
 pub struct Connection{
-    // address of other host
+    // id of the connection, chosen by the host that sent the syn
+    id : u32,
+    // address of other host, follows the peer if it changes
     addr : String,  
     // current sequence number
     sequence : u64,
     // current ack number for peer
     ack : u64,
-    // size of the sending window
-    window : u64,
+    // decides how many bytes may be in flight
+    congestion : Box<dyn CongestionController>,
+    // spaces the segments sent
+    pacer : Pacer,
+    // pacing rate in bytes per second, otherwise given by the congestion control or the window
+    pacing_rate : Option<u64>,
     // number of bytes in flight
     in_flight : u64,
+    // sequence number past the last byte the peer can hold, from the window in its latest ack
+    peer_window_end : u64,
+    // segments in flight, by sequence number, each one retransmitted on its own
+    unacked : BTreeMap<u64, Segment>,
+    // round trip time measured on the acks, gives the retransmission timeout
+    rtt : RttEstimator,
+    // timestamp option, if both hosts agreed on it during the handshake
+    timestamps : Option<Timestamps>,
+    // retransmission timeouts in a row, without the peer acking anything
+    timeouts : u32,
+    // most retransmission timeouts in a row before the connection is torn down
+    max_retransmits : u32,
+    // longest time sent data may stay unacked before the connection is torn down, if any
+    user_timeout : Option<Duration>,
+    // last time the peer acked something, or the time we started waiting for it
+    last_progress : Instant,
+    // last time we received anything from the peer
+    last_heard : Instant,
+    // probe the peer when it stays silent, disabled by default
+    keepalive : Option<Keepalive>,
+    // probes sent since we last heard from the peer
+    probes_sent : u32,
+    // longest time without hearing from the peer before the connection is torn down, if any
+    idle_timeout : Option<Duration>,
+    // number of duplicate acks received in a row
+    dup_acks : usize,
+    // while recovering from a loss, sequence number that must be acked to be done
+    recovery : Option<u64>,
+    // after slowing down on a congestion mark, sequence number that must be acked before slowing down again
+    ce_recovery : Option<u64>,
+    // data segments received marked congestion experienced, wrapping, echoed in every ack
+    ce_received : u32,
+    // latest count of marked segments echoed by the peer
+    ce_echoed : u32,
+    // time based loss detection, none until a segment is delivered
+    rack : Option<Rack>,
+    // time at which the last segment is sent again if no ack comes (tail loss probe), none when not armed
+    tlp_deadline : Option<Instant>,
+    // bytes delivered to the peer so far, acked or sacked
+    delivered : u64,
+    // time of the latest delivery
+    delivered_at : Instant,
+    // send time of the first segment of the current delivery rate interval
+    first_sent_at : Instant,
+    // delivered count up to which the application doesn't fill the window, 0 when it does,
+    // the acks don't grow the window and the rate samples are limited by the application until then
+    app_limited : u64,
+    // in order segments received and not acked yet
+    ack_pending : usize,
+    // time at which the delayed ack must leave at the latest
+    ack_deadline : Option<Instant>,
+    // number of in order segments received before acking them, 1 disables delayed acks
+    ack_frequency : usize,
+    // longest time an ack is delayed
+    ack_delay : Duration,
     // if we already sent a fin (stream closed to other host)
     sent_fin : bool,
     // if we received a fin
     received_fin : bool,
+    // why the connection was torn down, if it was
+    abort_reason : Option<ErrorKind>,
     // socket to other host
-    socket : Arc<UdpSocket>,
+    transport : Arc<Transport>,
     // buffer containing packets for this connection
-    receiver : Receiver<Packet>,
+    receiver : Receiver<(Packet, String, Ecn)>,
+    // our latest ack, sent by the receiving thread when we can't
+    shared_ack : Arc<Mutex<SharedAck>>,
     // data buffer
     buffer : Receiver<Vec<u8>>,
     // sender for the data buffer
     buffer_sender : Sender<Vec<u8>>,
+    // segments received after a gap, by sequence number, delivered once the gap is filled
+    out_of_order : BTreeMap<u64, Vec<u8>>,
+    // bytes we hold for the application, the peer never has more than that past our ack
+    receive_buffer : u64,
+    // bytes served in order that the application didn't take yet
+    unread : u64,
     // map of all connections, used to clean up when receiving fin
-    connections : Arc<Mutex<HashMap<String, Sender<Packet>>>>,
-    current_block : Option<Arc<Vec<u8>>>
+    connections : Connections,
+    current_block : Option<Arc<Vec<u8>>>,
+    // frames waiting to be packed in the next datagram
+    outbox : Vec<Packet>,
+    // encoded size of the frames in the outbox
+    outbox_len : usize
 }
 
 impl Read for Connection{
@@ -60,12 +333,21 @@ impl Read for Connection{
         }
         // try to get some data from the buffer if already available
         if let Ok(data) = self.buffer.try_recv(){
+            self.consumed(data.len());
             self.current_block = Some(Arc::new(data));
             return self.read(buf);
         }
         // loop until getting real data
-        while let Ok(false) = self.receive(None){}
+        loop{
+            match self.receive(None){
+                Ok(false) => continue,
+                // the peer is gone, unlike the end of the stream
+                Err(err) if err.kind() == ErrorKind::ConnectionAborted => return Err(err),
+                _ => break
+            }
+        }
         if let Ok(data) = self.buffer.try_recv(){
+            self.consumed(data.len());
             self.current_block = Some(Arc::new(data));
             return self.read(buf);
         }
@@ -86,56 +368,622 @@ impl Write for Connection{
 }
 
 impl Connection{
-    pub fn new(sequence : u64, ack : u64, socket : Arc<UdpSocket>, addr : String, receiver : Receiver<Packet>, connections : Arc<Mutex<HashMap<String, Sender<Packet>>>>) -> Connection{
+    pub fn new(id : u32, sequence : u64, ack : u64, transport : Arc<Transport>, addr : String, inbox : Inbox, connections : Connections) -> Connection{
         let (tx, rx) = channel();
-        Connection{sequence, 
-            ack, window : 4*(MAX_SIZE as u64), 
-            in_flight : 0, socket, addr, receiver, 
-            buffer : rx, buffer_sender : tx, received_fin : false, 
-            sent_fin : false, connections, current_block : None}
+        let connection = Connection{id, sequence, 
+            ack, congestion : Box::new(NewReno::new()), pacer : Pacer::new(), pacing_rate : None,
+            in_flight : 0, peer_window_end : sequence + RECEIVE_BUFFER, unacked : BTreeMap::new(), rtt : RttEstimator::new(), timestamps : None,
+            timeouts : 0, max_retransmits : MAX_RETRANSMITS, user_timeout : None, last_progress : Instant::now(),
+            last_heard : Instant::now(), keepalive : None, probes_sent : 0, idle_timeout : None, dup_acks : 0, recovery : None,
+            ce_recovery : None, ce_received : 0, ce_echoed : 0, rack : None, tlp_deadline : None,
+            delivered : 0, delivered_at : Instant::now(), first_sent_at : Instant::now(), app_limited : 0,
+            ack_pending : 0, ack_deadline : None, ack_frequency : ACK_FREQUENCY, ack_delay : ACK_DELAY, transport, addr, receiver : inbox.receiver, 
+            shared_ack : inbox.ack, buffer : rx, buffer_sender : tx, out_of_order : BTreeMap::new(), receive_buffer : RECEIVE_BUFFER, unread : 0, received_fin : false, 
+            abort_reason : None, sent_fin : false, connections, current_block : None,
+            outbox : Vec::new(), outbox_len : 0};
+        connection.share_ack(None);
+        connection
     }
 
     pub fn get_peer_addr(&self) -> String{
         self.addr.clone()
     }
 
+    pub fn get_id(&self) -> u32{
+        self.id
+    }
+
+    /**
+     * Why the connection was torn down, none if it wasn't: TimedOut when the peer stopped acking,
+     * ConnectionAborted when it stayed silent for too long, ConnectionReset when it reset the connection
+     */
+    pub fn get_abort_reason(&self) -> Option<ErrorKind>{
+        self.abort_reason
+    }
+
+    /**
+     * Start from an estimation already made, e.g. during the handshake
+     */
+    pub fn with_rtt(mut self, rtt : RttEstimator) -> Connection{
+        self.rtt = rtt;
+        self
+    }
+
+    /**
+     * Use another congestion control algorithm than NewReno
+     */
+    pub fn with_congestion(mut self, congestion : Box<dyn CongestionController>) -> Connection{
+        self.congestion = congestion;
+        self
+    }
+
+    /**
+     * Put the timestamp option in every packet, with our clock and the latest value received from the peer
+     */
+    pub fn with_timestamps(mut self, clock : TimestampClock, recent : u32) -> Connection{
+        self.timestamps = Some(Timestamps::new(clock, recent));
+        self.share_ack(None);
+        self
+    }
+
+    /**
+     * Ack every n in order segments instead of every one of them, 1 acks each segment right away
+     */
+    pub fn set_ack_frequency(&mut self, frequency : usize){
+        self.ack_frequency = usize::max(frequency, 1);
+    }
+
+    /**
+     * Hold this many bytes for the application, advertised to the peer from the next ack
+     */
+    pub fn set_receive_buffer(&mut self, size : u64){
+        self.receive_buffer = size;
+    }
+
+    /**
+     * Send at this many bytes per second at most, none paces the segments from the congestion control
+     */
+    pub fn set_pacing_rate(&mut self, rate : Option<u64>){
+        self.pacing_rate = rate;
+    }
+
+    /**
+     * Longest time an ack may be delayed while waiting for more segments
+     */
+    pub fn set_ack_delay(&mut self, delay : Duration){
+        self.ack_delay = delay;
+    }
+
+    /**
+     * Number of retransmission timeouts in a row after which the peer is considered gone
+     */
+    pub fn set_max_retransmits(&mut self, max_retransmits : u32){
+        self.max_retransmits = max_retransmits;
+    }
+
+    /**
+     * Longest time sent data may stay unacked before the peer is considered gone,
+     * checked whenever the retransmission timer expires
+     */
+    pub fn set_user_timeout(&mut self, timeout : Option<Duration>){
+        self.user_timeout = timeout;
+    }
+
+    /**
+     * Probe a silent peer while waiting for it, none disables the probes
+     */
+    pub fn set_keepalive(&mut self, keepalive : Option<Keepalive>){
+        self.keepalive = keepalive;
+    }
+
+    /**
+     * Longest time without hearing from the peer while waiting for it,
+     * after which the connection is torn down, none waits forever
+     */
+    pub fn set_idle_timeout(&mut self, timeout : Option<Duration>){
+        self.idle_timeout = timeout;
+    }
+
+    /**
+     * Smoothed round trip time to the peer, if measured yet
+     */
+    pub fn get_srtt(&self) -> Option<Duration>{
+        self.rtt.get_srtt()
+    }
+
+    /**
+     * Queue a frame, it leaves along with the other queued frames
+     * the next time the connection flushes, at the latest before waiting for the peer
+     */
+    fn queue(&mut self, packet : Packet) -> Result<(), Error>{
+        let encoding = self.transport.encoding();
+        let packet = match &self.timestamps{
+            Some(timestamps) => packet.with_option(timestamps.option()),
+            None => packet
+        };
+        let packet = if packet.is_ack(){
+            self.with_window(self.with_ce_count(packet))
+        }else{
+            packet
+        };
+        if packet.is_ack(){
+            // the peer gets our latest ack, nothing is owed anymore
+            self.ack_pending = 0;
+            self.ack_deadline = None;
+            // it waits in the outbox for our answer, the receiving thread sends it if none comes in time
+            self.share_ack(Some(Instant::now() + self.ack_delay));
+            // a newer ack, standalone or piggybacked on data, supersedes the queued ones
+            self.outbox.retain(|queued| !queued.is_pure_ack());
+            self.outbox_len = self.outbox.iter().map(|queued| queued.frame_len(encoding)).sum();
+        }
+        let len = packet.frame_len(encoding);
+        if !self.outbox.is_empty() && DATAGRAM_HEADER_SIZE + self.outbox_len + len > MAX_DATAGRAM{
+            self.flush()?;
+        }
+        self.outbox_len += len;
+        self.outbox.push(packet);
+        Ok(())
+    }
+
+    /**
+     * Echo the number of segments received marked congestion experienced, once there is one
+     */
+    fn with_ce_count(&self, packet : Packet) -> Packet{
+        if self.ce_received == 0{
+            return packet;
+        }
+        packet.with_option(PacketOption::CeCount(self.ce_received))
+    }
+
+    /**
+     * Tell the peer how much more we can hold past our ack
+     */
+    fn with_window(&self, packet : Packet) -> Packet{
+        let window = u64::min(self.window(), u32::MAX as u64) as u32;
+        packet.with_option(PacketOption::Window(window))
+    }
+
+    /**
+     * Free space in the receive buffer
+     */
+    fn window(&self) -> u64{
+        self.receive_buffer.saturating_sub(self.unread)
+    }
+
+    /**
+     * The application took some data, tell the peer once there is room for a segment again,
+     * as it may be waiting for it with nothing in flight
+     */
+    fn consumed(&mut self, len : usize){
+        let threshold = u64::min(MAX_SIZE as u64, self.receive_buffer / 2);
+        let window = self.window();
+        self.unread = self.unread.saturating_sub(len as u64);
+        if window < threshold && self.window() >= threshold && !self.received_fin && self.abort_reason.is_none(){
+            let ack = self.new_ack();
+            let _ = self.queue(ack).and_then(|_| self.flush());
+        }
+    }
+
+    /**
+     * Give our latest ack to the receiving thread, to be sent by deadline if it is delayed
+     */
+    fn share_ack(&self, deadline : Option<Instant>){
+        let ack = self.with_window(self.with_ce_count(self.new_ack()));
+        self.shared_ack.lock().unwrap().update(ack, self.timestamps.clone(), self.addr.clone(), deadline);
+    }
+
+    /**
+     * Send all the queued frames, packed in as few datagrams as possible
+     */
+    fn flush(&mut self) -> Result<(), Error>{
+        if self.outbox.is_empty(){
+            return Ok(());
+        }
+        let res = self.transport.send_frames(&self.outbox, self.id, &self.addr);
+        if self.outbox.iter().any(|queued| queued.is_ack()){
+            self.shared_ack.lock().unwrap().sent();
+        }
+        self.outbox.clear();
+        self.outbox_len = 0;
+        res
+    }
+
     /**
      * Send a single part of data
      */
-    fn send_packet(&mut self, content : &Vec<u8>, init_sequence : u64) -> Result<(), Error>{
+    fn send_packet(&mut self, content : &[u8], init_sequence : u64) -> Result<(), Error>{
         let offset = (self.sequence + self.in_flight - init_sequence) as usize;
         let len: usize = content.len();
-        let rem_window = (self.window - self.in_flight) as usize;
+        let rem_window = self.send_window() as usize;
         let size_sending = usize::min(
             len - offset, 
             usize::min(MAX_SIZE, rem_window)
         );
         let sub = offset..offset+size_sending;
+        // the frame owns its content, the segment is copied out of the message each time it is sent
         let buf = content[sub].to_vec();
-        let packet = Packet::new_data(buf, self.sequence + self.in_flight);
+        let sequence = self.sequence + self.in_flight;
+        let packet = self.with_sack(Packet::new_data(buf, sequence, self.ack));
+        let now = Instant::now();
+        if self.unacked.is_empty(){
+            // nothing in flight, the delivery rate is measured from now on
+            self.first_sent_at = now;
+            self.delivered_at = now;
+        }
         self.in_flight += size_sending as u64;
-        self.socket.send_to(&packet.to_bytes(), self.addr.clone())?;
+        let segment = self.new_segment(size_sending as u64, now);
+        self.unacked.insert(sequence, segment);
+        self.pacer.spend(size_sending as u64, self.target_rate());
+        self.arm_tail_loss_probe();
+        self.queue(packet)
+    }
+
+    /**
+     * Send again the segments considered lost, and only them. A segment is lost when its
+     * retransmission timer expired, when duplicate or partial acks point at it, when the peer
+     * sacked enough segments sent after it, or when a segment sent after it was delivered
+     * and it is late by more than the reordering window (RACK).
+     * Lost segments leave oldest first, as the congestion window and the pacer allow, return
+     * how long the pacer holds back the next one, zero if the window does or nothing is left.
+     * If nothing was heard for a while, the last segment is sent again to probe for a tail loss
+     */
+    fn retransmit_lost(&mut self, content : &[u8], init_sequence : u64) -> Result<Duration, Error>{
+        let now = Instant::now();
+        let rto = self.rtt.rto();
+        let reorder_window = self.reorder_window();
+        let mut sacked_above = 0;
+        let mut expired = false;
+        let mut newly_lost = false;
+        for (sequence, segment) in self.unacked.iter_mut().rev(){
+            if segment.sacked{
+                sacked_above += 1;
+                continue;
+            }
+            if segment.lost{
+                // already waiting for the window
+                continue;
+            }
+            let rack_lost = self.rack.as_ref().is_some_and(|rack|
+                rack.sent_after(segment.sent_at, sequence + segment.len) && now >= segment.sent_at + rack.rtt + reorder_window);
+            if now >= segment.sent_at + rto{
+                expired = true;
+                segment.lost = true;
+            }else if rack_lost || (!segment.retransmitted && sacked_above >= DUP_THRESHOLD){
+                newly_lost = true;
+                segment.lost = true;
+            }
+        }
+        if expired{
+            // no more probes until an ack comes
+            self.tlp_deadline = None;
+            self.congestion.on_rto(self.in_flight);
+            self.timer_expired()?;
+        }else if newly_lost{
+            self.enter_recovery();
+        }
+        let lost : Vec<(u64, u64)> = self.unacked.iter()
+            .filter(|(_, segment)| segment.lost)
+            .map(|(sequence, segment)| (*sequence, segment.len))
+            .collect();
+        if lost.is_empty() && self.tlp_deadline.is_some_and(|tlp_deadline| now >= tlp_deadline){
+            self.tlp_deadline = None;
+            // nothing came after the tail to reveal its loss, probe with the last segment,
+            // whatever the window as nothing else would reveal it (RFC 8985)
+            if let Some((sequence, segment)) = self.unacked.iter().rev().find(|(_, segment)| !segment.sacked){
+                let (sequence, len) = (*sequence, segment.len);
+                self.resend(content, init_sequence, sequence, len)?;
+            }
+        }
+        for (sequence, len) in lost{
+            let pacing_delay = self.pacer.delay(self.target_rate());
+            if !pacing_delay.is_zero(){
+                return Ok(pacing_delay);
+            }
+            if self.congestion.cwnd().saturating_sub(self.pipe()) < len{
+                // the next acks open the window again
+                break;
+            }
+            self.resend(content, init_sequence, sequence, len)?;
+        }
+        Ok(Duration::ZERO)
+    }
+
+    /**
+     * Send a segment again, it is in flight once more
+     */
+    fn resend(&mut self, content : &[u8], init_sequence : u64, sequence : u64, len : u64) -> Result<(), Error>{
+        let offset = (sequence - init_sequence) as usize;
+        let buf = content[offset..offset + len as usize].to_vec();
+        let packet = self.with_sack(Packet::new_data(buf, sequence, self.ack));
+        let segment = Segment{retransmitted : true, ..self.new_segment(len, Instant::now())};
+        self.unacked.insert(sequence, segment);
+        self.pacer.spend(len, self.target_rate());
+        self.queue(packet)
+    }
+
+    /**
+     * A segment sent now, stamped with what was delivered so far to measure the delivery rate
+     */
+    fn new_segment(&self, len : u64, now : Instant) -> Segment{
+        Segment{len, sent_at : now, sacked : false, retransmitted : false, lost : false,
+            delivered : self.delivered, delivered_at : self.delivered_at, first_sent_at : self.first_sent_at, app_limited : self.app_limited != 0}
+    }
+
+    /**
+     * Segments were acked or sacked for the first time, feed the loss detection and
+     * give the congestion control a sample of the delivery rate, measured on the segment sent last
+     */
+    fn on_delivered(&mut self, segments : Vec<(u64, Segment)>){
+        let now = Instant::now();
+        let mut acked = 0;
+        for (sequence, segment) in &segments{
+            self.rack_update(segment.sent_at, sequence + segment.len, segment.retransmitted);
+            acked += segment.len;
+        }
+        let Some((_, latest)) = segments.into_iter().max_by_key(|(_, segment)| segment.sent_at) else{
+            return;
+        };
+        self.delivered += acked;
+        self.delivered_at = now;
+        if self.app_limited != 0 && self.delivered > self.app_limited{
+            self.app_limited = 0;
+        }
+        // the next interval starts with this segment
+        self.first_sent_at = latest.sent_at;
+        // data can't be delivered faster than it was sent, nor than it was acked
+        let send_elapsed = latest.sent_at.saturating_duration_since(latest.first_sent_at);
+        let ack_elapsed = now.saturating_duration_since(latest.delivered_at);
+        // too short to tell anything, e.g. acks compressed on the way back
+        let interval = Some(Duration::max(send_elapsed, ack_elapsed))
+            .filter(|interval| !interval.is_zero() && self.rtt.get_min_rtt().is_none_or(|min_rtt| *interval >= min_rtt));
+        let sample = RateSample{
+            acked,
+            delivered : self.delivered - latest.delivered,
+            interval,
+            rtt : now.saturating_duration_since(latest.sent_at),
+            prior_delivered : latest.delivered,
+            total_delivered : self.delivered,
+            in_flight : self.pipe(),
+            app_limited : latest.app_limited
+        };
+        self.congestion.on_delivery(&sample);
+    }
+
+    /**
+     * A loss was detected, the window shrinks once until everything sent so far is acked
+     */
+    fn enter_recovery(&mut self){
+        if self.recovery.is_none(){
+            self.recovery = Some(self.sequence + self.in_flight);
+            if !self.ce_reduced(){
+                // unless a congestion mark already slowed us down for this window
+                self.congestion.on_loss(self.in_flight);
+            }
+        }
+    }
+
+    /**
+     * A congestion mark already slowed us down for the data in flight
+     */
+    fn ce_reduced(&self) -> bool{
+        self.ce_recovery.is_some_and(|end| self.sequence < end)
+    }
+
+    /**
+     * Slow down when the peer echoes new congestion marks, at most once per window
+     * and not while recovering from a loss, as a loss would (RFC 3168)
+     */
+    fn update_ce_echoed(&mut self, options : &[PacketOption]){
+        let Some(count) = ce_count_of(options) else{
+            return;
+        };
+        // the count wraps around, and an older ack may arrive late
+        if (count.wrapping_sub(self.ce_echoed) as i32) <= 0{
+            return;
+        }
+        self.ce_echoed = count;
+        if self.recovery.is_some() || self.ce_reduced(){
+            return;
+        }
+        self.ce_recovery = Some(self.sequence + self.in_flight);
+        self.congestion.on_ce(self.in_flight);
+    }
+
+    /**
+     * Bytes that may be sent now, as the congestion window and the window of the peer allow
+     */
+    fn send_window(&self) -> u64{
+        let peer_window = self.peer_window_end.saturating_sub(self.sequence + self.in_flight);
+        u64::min(self.congestion.cwnd().saturating_sub(self.pipe()), peer_window)
+    }
+
+    /**
+     * Rate at which the segments should leave, in bytes per second: the rate set for the connection,
+     * or the one of the congestion control (BBR), or twice the window per min RTT so that
+     * NewReno and CUBIC spread it over the round trip, none until the round trip time is known
+     */
+    fn target_rate(&self) -> Option<f64>{
+        self.pacing_rate.map(|rate| rate as f64)
+            .or_else(|| self.congestion.pacing_rate())
+            .or_else(|| self.rtt.get_min_rtt()
+                .filter(|min_rtt| !min_rtt.is_zero())
+                .map(|min_rtt| PACING_GAIN * self.congestion.cwnd() as f64 / min_rtt.as_secs_f64()))
+    }
+
+    /**
+     * Bytes in flight that the peer doesn't hold yet, sacked segments left the network
+     * and so did the lost ones until they are sent again (RFC 6675)
+     */
+    fn pipe(&self) -> u64{
+        let left : u64 = self.unacked.values().filter(|segment| segment.sacked || segment.lost).map(|segment| segment.len).sum();
+        self.in_flight.saturating_sub(left)
+    }
+
+    /**
+     * The retransmission timer expired, back off or give up on the peer once the budget is spent
+     */
+    fn timer_expired(&mut self) -> Result<(), Error>{
+        self.timeouts += 1;
+        let too_long = self.user_timeout.is_some_and(|timeout| self.last_progress.elapsed() >= timeout);
+        if self.timeouts > self.max_retransmits || too_long{
+            return Err(self.abort(ErrorKind::TimedOut, "Peer not answering"));
+        }
+        self.rtt.backoff();
         Ok(())
     }
 
+    /**
+     * Tear down a connection whose peer doesn't answer anymore
+     */
+    fn abort(&mut self, kind : ErrorKind, reason : &str) -> Error{
+        self.abort_reason = Some(kind);
+        self.connections.lock().unwrap().remove(&self.id);
+        self.unacked.clear();
+        self.in_flight = 0;
+        self.outbox.clear();
+        self.outbox_len = 0;
+        Error::new(kind, reason)
+    }
+
+    /**
+     * An ack for everything received, its sequence number being the next one we'll send
+     */
+    fn new_ack(&self) -> Packet{
+        self.with_sack(Packet::new_ack(self.sequence + self.in_flight, self.ack))
+    }
+
+    /**
+     * Time at which the next keepalive probe is due, probes are only needed when nothing is in flight
+     */
+    fn keepalive_deadline(&self) -> Option<Instant>{
+        self.keepalive
+            .filter(|_| self.in_flight == 0)
+            .map(|keepalive| self.last_heard + keepalive.idle + keepalive.interval * self.probes_sent)
+    }
+
+    /**
+     * Send a keepalive probe, an ack for a sequence number the peer already got so that
+     * it answers, or give up on the peer if too many probes stayed unanswered
+     */
+    fn probe(&mut self) -> Result<(), Error>{
+        let count = self.keepalive.map_or(0, |keepalive| keepalive.count);
+        if self.probes_sent >= count{
+            return Err(self.abort(ErrorKind::ConnectionAborted, "Keepalive probes unanswered"));
+        }
+        self.probes_sent += 1;
+        let probe = Packet::new_ack(self.sequence - 1, self.ack);
+        self.queue(probe)
+    }
+
+    /**
+     * Time left before a segment in flight must be retransmitted, because of
+     * its retransmission timer, the reordering window or the tail loss probe
+     */
+    fn next_timeout(&self) -> Duration{
+        let reorder_window = self.reorder_window();
+        // the lost segments already wait for the window
+        self.unacked.iter()
+            .filter(|(_, segment)| !segment.sacked && !segment.lost)
+            .flat_map(|(sequence, segment)| {
+                let reordered = self.rack.as_ref()
+                    .filter(|rack| rack.sent_after(segment.sent_at, sequence + segment.len))
+                    .map(|rack| segment.sent_at + rack.rtt + reorder_window);
+                [Some(segment.sent_at + self.rtt.rto()), reordered]
+            })
+            .flatten()
+            .chain(self.tlp_deadline)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .unwrap_or(self.rtt.rto())
+    }
+
+    /**
+     * How late a segment may be delivered compared to one sent after it before it is considered lost
+     */
+    fn reorder_window(&self) -> Duration{
+        match (self.rtt.get_min_rtt(), self.rtt.get_srtt()){
+            (Some(min_rtt), Some(srtt)) => Duration::min(min_rtt / 4, srtt),
+            _ => Duration::ZERO
+        }
+    }
+
+    /**
+     * A segment was delivered, remember it if it is the latest one sent
+     */
+    fn rack_update(&mut self, sent_at : Instant, end : u64, retransmitted : bool){
+        let rtt = sent_at.elapsed();
+        if retransmitted && self.rtt.get_min_rtt().is_some_and(|min_rtt| rtt < min_rtt){
+            // too early to be for the retransmission, the original was delivered
+            return;
+        }
+        if self.rack.as_ref().is_none_or(|rack| !rack.sent_after(sent_at, end)){
+            self.rack = Some(Rack{sent_at, end, rtt});
+        }
+    }
+
+    /**
+     * Arm the tail loss probe timer for about two round trips from now, if anything is in flight
+     */
+    fn arm_tail_loss_probe(&mut self){
+        let outstanding = self.unacked.values().filter(|segment| !segment.sacked).count();
+        if outstanding == 0 || self.recovery.is_some(){
+            self.tlp_deadline = None;
+            return;
+        }
+        let rto = self.rtt.rto();
+        let pto = match self.rtt.get_srtt(){
+            None => rto,
+            Some(srtt) => {
+                let mut pto = Duration::max(2 * srtt, MIN_PTO);
+                if outstanding == 1{
+                    // a lone segment may wait for a delayed ack
+                    pto += ACK_DELAY;
+                }
+                Duration::min(pto, rto)
+            }
+        };
+        self.tlp_deadline = Some(Instant::now() + pto);
+    }
+
     /**
      * Send some data to another host
      */
     pub fn send(&mut self, content : Vec<u8>) -> Result<(), Error>{
         let len = content.len() as u64;
         let init_sequence =  self.sequence;
+        self.last_progress = Instant::now();
 
-        // go-back-n implementation
+        // selective repeat implementation
         while self.sequence < init_sequence + len{
+            // lost segments go first, checked after every packet so that a flow of acks can't hold them back
+            let mut pacing_delay = self.retransmit_lost(&content, init_sequence)?;
+            let lost_pending = self.unacked.values().any(|segment| segment.lost);
             let mut remaining = self.sequence + self.in_flight - init_sequence;
-            while self.in_flight < self.window && remaining < len{
+            while pacing_delay.is_zero() && !lost_pending && self.send_window() > 0 && remaining < len{
+                pacing_delay = self.pacer.delay(self.target_rate());
+                if !pacing_delay.is_zero(){
+                    // the next segment leaves later, spread over the round trip
+                    break;
+                }
                 self.send_packet(&content, init_sequence)?;
                 remaining = self.sequence + self.in_flight - init_sequence;
             }
-            let data = self.receive(Some(RTO));
-            if let Err(_) = data{
-                // rto reached
-                self.in_flight = 0;
+            if remaining >= len && self.send_window() > 0{
+                // everything is sent without filling the window, the delivery rate measures the application
+                self.app_limited = u64::max(self.delivered + self.in_flight, 1);
+            }
+            let mut timeout = self.next_timeout();
+            if !pacing_delay.is_zero(){
+                timeout = Duration::min(timeout, pacing_delay);
+            }
+            match self.receive(Some(timeout)){
+                Err(err) if matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::NotConnected | ErrorKind::ConnectionAborted) => return Err(err),
+                Err(err) if err.kind() == ErrorKind::TimedOut && self.in_flight == 0 && self.send_window() == 0 => {
+                    // the window of the peer is closed and the ack opening it may be lost, ask again
+                    let probe = Packet::new_ack(self.sequence - 1, self.ack);
+                    self.queue(probe)?;
+                },
+                _ => {}
             }
         }
         Ok(())
@@ -145,82 +993,325 @@ impl Connection{
      * Core receive loop, 
      * return Ok(true) if real data was received
      * return Ok(false) if an ack was received
-     * return Err otherwise (fin/reset)
+     * return Err otherwise (fin/reset), with TimedOut if nothing came in time
+     * and NotConnected if the connection was removed
      */
     fn receive(&mut self, timeout : Option<time::Duration>) -> Result<bool, Error>{
-        loop{
-            let packet ;
-            if timeout.is_none(){
-                let res = self.receiver.recv();
-                if res.is_err(){
-                    return Err(Error::new(ErrorKind::Interrupted, "Error"));
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let (packet, from, ecn) = loop{
+            // whatever is queued must leave before we wait for the peer
+            self.flush()?;
+            if self.ack_deadline.is_some() && !self.shared_ack.lock().unwrap().is_delayed(){
+                // the receiving thread sent the delayed ack meanwhile
+                self.ack_pending = 0;
+                self.ack_deadline = None;
+            }
+            // wake up in time for a delayed ack, a keepalive probe or the idle timeout
+            let idle_deadline = self.idle_timeout.map(|timeout| self.last_heard + timeout);
+            let wait = [deadline, self.ack_deadline, self.keepalive_deadline(), idle_deadline].into_iter().flatten().min();
+            let res = match wait{
+                None => self.receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(wait) => self.receiver.recv_timeout(wait.saturating_duration_since(Instant::now()))
+            };
+            match res{
+                Ok(res) => break res,
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::new(ErrorKind::NotConnected, "Connection removed")),
+                Err(RecvTimeoutError::Timeout) => {
+                    let now = Instant::now();
+                    if self.ack_deadline.is_some_and(|ack_deadline| now >= ack_deadline){
+                        // the delayed ack can't wait any longer, unless the receiving thread just sent it
+                        if self.shared_ack.lock().unwrap().is_delayed(){
+                            let ack = self.new_ack();
+                            self.queue(ack)?;
+                        }
+                        self.ack_pending = 0;
+                        self.ack_deadline = None;
+                    }else if idle_deadline.is_some_and(|idle_deadline| now >= idle_deadline){
+                        return Err(self.abort(ErrorKind::ConnectionAborted, "Idle timeout"));
+                    }else if self.keepalive_deadline().is_some_and(|keepalive_deadline| now >= keepalive_deadline){
+                        self.probe()?;
+                    }else{
+                        return Err(Error::new(ErrorKind::TimedOut, ""));
+                    }
                 }
-                packet = res.unwrap();
-            }else{
-                let res = self.receiver.recv_timeout(timeout.unwrap());
-                if let Err(_) = res{
-                    return Err(Error::new(ErrorKind::Interrupted, ""));
+            }
+        };
+        self.last_heard = Instant::now();
+        self.probes_sent = 0;
+        // an ack can't cover more than what we sent, a bogus one must not underflow the flight
+        let acked = u64::min(packet.get_acked(), self.sequence + self.in_flight);
+        // nor end inside a segment, the scoreboard only holds whole segments
+        let acked = match self.unacked.range(..acked).next_back(){
+            Some((sequence, segment)) if sequence + segment.len > acked => *sequence,
+            _ => acked
+        };
+        let mut timestamp_sample = None;
+        if let (Some(timestamps), Some((value, echo))) = (&mut self.timestamps, timestamp_of(packet.get_options())){
+            if !packet.is_reset() && is_older(value, timestamps.recent){
+                // old duplicate from an earlier incarnation of the sequence numbers (PAWS)
+                if !packet.is_pure_ack(){
+                    let ack = self.new_ack();
+                    let _ = self.queue(ack);
                 }
-                packet = res.unwrap();
-            }
-            if packet.is_ack(){
-                if packet.get_acked() > self.sequence{
-                    // correct sequence, move on in window
-                    self.in_flight -= packet.get_acked() - self.sequence;
-                    self.sequence = packet.get_acked();
-                    return Ok(false);
+                return Ok(false);
+            }
+            if packet.get_sequence() <= self.ack{
+                timestamps.recent = value;
+            }
+            if packet.is_ack() && acked > self.sequence{
+                // valid even when the acked segment was retransmitted, but only to the millisecond
+                timestamp_sample = Some(timestamps.clock.since(echo));
+            }
+        }
+        if let Some(window) = window_of(packet.get_options()).filter(|_| packet.is_ack() && acked >= self.sequence){
+            // not an older ack, the peer has room up to there
+            self.peer_window_end = acked + window as u64;
+        }
+        // the peer moved (e.g. NAT rebinding), follow it once it makes progress from its new address,
+        // a stale or forged packet from elsewhere doesn't steal the connection
+        let progress = (packet.is_ack() && acked > self.sequence) || (packet.get_sequence() == self.ack && packet.get_size() > 0);
+        if from != self.addr && progress{
+            self.addr = from;
+            self.share_ack(self.ack_deadline);
+        }
+        if packet.is_ack() && acked > self.sequence{
+            // correct sequence, move on in window, whether the ack
+            // is standalone or piggybacked on some data
+            let newly_acked = acked - self.sequence;
+            self.in_flight -= newly_acked;
+            self.sequence = acked;
+            // measure on the latest segment acked, the echoed timestamp only tells
+            // which transmission is acked when it was retransmitted (Karn's rule)
+            let sample = match self.unacked.range(..acked).rev().find(|(sequence, segment)| *sequence + segment.len <= acked){
+                Some((_, segment)) if !segment.retransmitted => Some(segment.sent_at.elapsed()),
+                Some(_) => timestamp_sample,
+                // the syn-ack or the fin, measured by the caller unless it was sent again
+                None => timestamp_sample.filter(|_| self.timeouts > 0)
+            };
+            if let Some(rtt) = sample{
+                self.rtt.sample(rtt);
+            }
+            let delivered : Vec<(u64, Segment)> = self.unacked.range(..acked)
+                .filter(|(sequence, segment)| *sequence + segment.len <= acked && !segment.sacked)
+                .map(|(sequence, segment)| (*sequence, segment.clone()))
+                .collect();
+            self.unacked.retain(|sequence, segment| sequence + segment.len > acked);
+            self.on_delivered(delivered);
+            self.dup_acks = 0;
+            self.timeouts = 0;
+            self.last_progress = Instant::now();
+            match self.recovery{
+                Some(recovery) if acked >= recovery => {
+                    // everything sent before the loss is acked, recovery is over
+                    self.recovery = None;
+                },
+                Some(_) => {
+                    // partial ack, the next hole was lost too
+                    self.mark_lost(acked);
+                },
+                // the application didn't fill the window, the ack tells nothing about the path
+                None if self.app_limited != 0 => {},
+                None => self.congestion.on_ack(newly_acked, &self.rtt)
+            }
+            self.arm_tail_loss_probe();
+        }else if packet.is_pure_ack() && packet.get_acked() == self.sequence && self.in_flight > 0{
+            self.dup_acks += 1;
+            if self.dup_acks == DUP_THRESHOLD && self.recovery.is_none(){
+                // fast retransmit, without waiting for the timer
+                self.enter_recovery();
+                self.mark_lost(self.sequence);
+            }
+        }
+        if packet.is_ack() && self.update_scoreboard(packet.get_options()){
+            self.arm_tail_loss_probe();
+        }
+        if packet.is_ack(){
+            self.update_ce_echoed(packet.get_options());
+        }
+        if ecn == Ecn::Ce && packet.get_size() > 0{
+            self.ce_received = self.ce_received.wrapping_add(1);
+        }
+        if packet.is_pure_ack(){
+            // it carries no data so it must not be acked back, keepalive probes
+            // and acks overtaken by data were answered by the receiving thread
+            return Ok(false);
+        }
+        if packet.get_sequence() == self.ack{
+            // data packet or reset or fin
+            if packet.is_reset(){
+                self.abort_reason = Some(ErrorKind::ConnectionReset);
+                return Err(Error::new(ErrorKind::ConnectionReset, ""))
+            }
+            if packet.is_fin(){
+                //println!("Received fin");
+                self.received_fin = true;
+                // the fin takes one sequence number, whatever we send next acks it
+                self.ack += 1;
+                let ack = self.new_ack();
+                self.queue(ack)?;
+                self.flush()?;
+                // maintain state during 2*msl if fin_sent = true
+                if self.sent_fin{
+                    let arc = self.connections.clone();
+                    let id = self.id;
+                    thread::spawn(move ||{
+                        sleep(3*MSL);
+                        arc.lock().unwrap().remove(&id);
+                    });
                 }
+                return Err(Error::new(ErrorKind::Interrupted, "Fin"))
             }
-            if packet.get_sequence() == self.ack{
-                // data packet or reset or fin
-                if packet.is_reset(){
-                    return Err(Error::new(ErrorKind::ConnectionReset, ""))
+            self.ack += packet.get_size();
+            // serve data to application
+            self.unread += packet.get_size();
+            let _ = self.buffer_sender.send(packet.get_content());
+            // the gap may be filled, serve what was waiting behind it
+            let filled_gap = !self.out_of_order.is_empty();
+            self.deliver_out_of_order();
+            self.ack_pending += 1;
+            // a congestion mark is echoed right away, so that the peer slows down in time
+            if self.ack_pending < self.ack_frequency && !filled_gap && ecn != Ecn::Ce{
+                // delayed, more data or our answer may come soon enough to carry it
+                if self.ack_deadline.is_none(){
+                    self.ack_deadline = Some(Instant::now() + self.ack_delay);
                 }
-                if packet.is_fin(){
-                    //println!("Received fin");
-                    self.received_fin = true;
-                    let ack = Packet::new_ack(self.sequence, self.ack+1);
-                    self.socket.send(&ack.to_bytes())?;
-                    // maintain state during 2*msl if fin_sent = true
-                    if self.sent_fin{
-                        let arc = self.connections.clone();
-                        let addr = self.addr.clone();
-                        thread::spawn(move ||{
-                            sleep(3*MSL);
-                            arc.lock().unwrap().remove(&addr);
-                        });
+                // the receiving thread sends it in time if we don't
+                self.share_ack(self.ack_deadline);
+                return Ok(true);
+            }
+            // the ack waits in the outbox, so that it can travel with our answer
+            let ack = self.new_ack();
+            let err = self.queue(ack);
+            if err.is_err(){
+                return Err(Error::new(ErrorKind::Interrupted, "No data"));
+            }
+            Ok(true)
+        }else{
+            let repeated_fin = packet.is_fin() && self.received_fin;
+            if packet.get_sequence() > self.ack && packet.get_size() > 0 && !packet.is_fin() && !packet.is_reset(){
+                self.buffer_out_of_order(packet);
+            }
+            // resend ack, telling the peer about the gap and what we hold after it
+            let ack = self.new_ack();
+            let _ = self.queue(ack);
+            if repeated_fin{
+                // our ack of its fin was lost, the stream is over all the same
+                return Err(Error::new(ErrorKind::Interrupted, "Fin"));
+            }
+            Ok(false)
+        }
+    }
+
+    /**
+     * Flag a segment for retransmission, unless it was already sent again or the peer holds it
+     */
+    fn mark_lost(&mut self, sequence : u64){
+        if let Some(segment) = self.unacked.get_mut(&sequence){
+            if !segment.retransmitted && !segment.sacked{
+                segment.lost = true;
+            }
+        }
+    }
+
+    /**
+     * Mark the segments in flight that the peer holds according to the SACK blocks,
+     * return whether any segment was newly sacked
+     */
+    fn update_scoreboard(&mut self, options : &[PacketOption]) -> bool{
+        let mut delivered = Vec::new();
+        for option in options{
+            if let PacketOption::Sack(blocks) = option{
+                for (start, end) in blocks{
+                    for (sequence, segment) in self.unacked.range_mut(*start..*end){
+                        if sequence + segment.len <= *end && !segment.sacked{
+                            segment.sacked = true;
+                            delivered.push((*sequence, segment.clone()));
+                        }
                     }
-                    return Err(Error::new(ErrorKind::Interrupted, "Fin"))
                 }
-                let ack = Packet::new_ack(self.sequence, self.ack + packet.get_size());
-                let err = self.socket.send_to(&ack.to_bytes(), self.addr.clone());
-                if let Err(_) = err{
-                    return Err(Error::new(ErrorKind::Interrupted, "No data"));
+            }
+        }
+        let newly_sacked = !delivered.is_empty();
+        self.on_delivered(delivered);
+        newly_sacked
+    }
+
+    /**
+     * Add the blocks of out of order data we hold to an outgoing ack
+     */
+    fn with_sack(&self, packet : Packet) -> Packet{
+        let mut blocks : Vec<(u64, u64)> = Vec::new();
+        for (sequence, content) in &self.out_of_order{
+            let end = sequence + content.len() as u64;
+            if let Some(last) = blocks.last_mut(){
+                if *sequence <= last.1{
+                    // contiguous with the previous block
+                    last.1 = u64::max(last.1, end);
+                    continue;
                 }
-                self.ack += packet.get_size();
-                // serve data to application
-                let _ = self.buffer_sender.send(packet.get_content());
-                return Ok(true)
-            }else{
-                // resend ack
-                let ack = Packet::new_ack(self.sequence, self.ack);
-                let _ = self.socket.send_to(&ack.to_bytes(), self.addr.clone());
-                return Ok(false);
             }
+            if blocks.len() == MAX_SACK_BLOCKS{
+                break;
+            }
+            blocks.push((*sequence, end));
+        }
+        if blocks.is_empty(){
+            return packet;
         }
+        packet.with_option(PacketOption::Sack(blocks))
     }
 
     /**
-     * Receive some content from this connection
+     * Keep a segment received after a gap, as long as it fits in the window we advertised
+     */
+    fn buffer_out_of_order(&mut self, packet : Packet){
+        let sequence = packet.get_sequence();
+        let size = packet.get_size();
+        let window = self.window();
+        let buffered : u64 = self.out_of_order.values().map(|content| content.len() as u64).sum();
+        // the segment starts past our ack, compare distances so that a bogus sequence can't overflow
+        if sequence - self.ack > window.saturating_sub(size) || buffered + size > window{
+            return;
+        }
+        self.out_of_order.entry(sequence).or_insert_with(|| packet.get_content());
+    }
+
+    /**
+     * Serve in order the buffered segments that are now contiguous with the data received
+     */
+    fn deliver_out_of_order(&mut self){
+        while let Some(entry) = self.out_of_order.first_entry(){
+            let sequence = *entry.key();
+            if sequence > self.ack{
+                break;
+            }
+            let content = entry.remove();
+            let end = sequence + content.len() as u64;
+            if end <= self.ack{
+                // already received through a retransmission
+                continue;
+            }
+            let skip = (self.ack - sequence) as usize;
+            self.ack = end;
+            self.unread += (content.len() - skip) as u64;
+            let _ = self.buffer_sender.send(content[skip..].to_vec());
+        }
+    }
+
+    /**
+     * Receive some content from this connection, RecvError once no more will come,
+     * whether the peer closed the stream or the connection was torn down (see get_abort_reason)
      */
     pub fn recv(&mut self) -> Result<Vec<u8>, RecvError>{
         // try to get some data from the buffer if already available
         if let Ok(data) = self.buffer.try_recv(){
+            self.consumed(data.len());
             return Ok(data);
         }
         // loop until getting real data
         while let Ok(false) = self.receive(None){}
         if let Ok(data) = self.buffer.try_recv(){
+            self.consumed(data.len());
             return Ok(data);
         }
         // no more data, end of stream
@@ -232,19 +1323,35 @@ impl Connection{
      */
     pub fn close(&mut self) -> Result<(), std::io::Error>{
         //println!("Sending fin");
-        loop {
-            let fin = Packet::new_fin(self.sequence);
-            self.socket.send_to(&fin.to_bytes(), self.addr.clone())?;
-            let data = self.receive(Some(RTO));
-            if let Err(_) = data{
-                self.sent_fin = true;
-                if self.received_fin{
-                    let mut map = self.connections.lock().unwrap();
-                    map.remove(&self.addr);
-                }
-                break;
+        let fin_sequence = self.sequence;
+        // the fin takes one sequence number, so that its ack can be told apart
+        self.in_flight = 1;
+        let mut sent_at = Instant::now();
+        self.last_progress = sent_at;
+        let mut retransmitted = false;
+        self.queue(Packet::new_fin(fin_sequence, self.ack))?;
+        while self.sequence <= fin_sequence{
+            let timeout = (sent_at + self.rtt.rto()).saturating_duration_since(Instant::now());
+            match self.receive(Some(timeout)){
+                Err(err) if err.kind() == ErrorKind::TimedOut => {
+                    self.timer_expired()?;
+                    self.queue(Packet::new_fin(fin_sequence, self.ack))?;
+                    sent_at = Instant::now();
+                    retransmitted = true;
+                },
+                Err(err) if matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::NotConnected | ErrorKind::ConnectionAborted) => break,
+                // data or the fin of the peer, keep waiting for our fin to be acked
+                _ => {}
             }
         }
+        if self.sequence > fin_sequence && !retransmitted{
+            self.rtt.sample(sent_at.elapsed());
+        }
+        self.sent_fin = true;
+        if self.received_fin{
+            let mut map = self.connections.lock().unwrap();
+            map.remove(&self.id);
+        }
         Ok(())
     }
 
@@ -252,27 +1359,393 @@ impl Connection{
      * Send a reset packet, closing immediatly the connection
      * May create losses
      */
-    pub fn reset(self) -> Result<(), std::io::Error>{
+    pub fn reset(mut self) -> Result<(), std::io::Error>{
         let reset = Packet::new_reset(self.sequence);
-        self.socket.send_to(&reset.to_bytes(), self.addr)?;
-        Ok(())
+        self.queue(reset)?;
+        self.flush()
     }
 
     /**
      * Accept an incoming connection
      */
     pub fn accept(&mut self) -> Result<(), std::io::Error>{
-        loop{
-            self.ack += 1;
-            let synack = Packet::new_synack(self.sequence, self.ack);
-            self.socket.send_to(&synack.to_bytes(), self.addr.clone())?;
-            self.in_flight += 1;
-            let not_ack = self.receive(Some(RTO))?;
-            if !not_ack{
-                break;
+        self.ack += 1;
+        let synack_sequence = self.sequence;
+        // the syn-ack takes one sequence number too
+        self.in_flight = 1;
+        let mut sent_at = Instant::now();
+        self.last_progress = sent_at;
+        let mut retransmitted = false;
+        self.queue(Packet::new_synack(synack_sequence, self.ack))?;
+        // the ack of the syn-ack may be lost, any data acking it completes the handshake as well
+        while self.sequence == synack_sequence{
+            let timeout = (sent_at + self.rtt.rto()).saturating_duration_since(Instant::now());
+            match self.receive(Some(timeout)){
+                Err(err) if err.kind() == ErrorKind::TimedOut => {
+                    self.timer_expired()?;
+                    self.queue(Packet::new_synack(synack_sequence, self.ack))?;
+                    sent_at = Instant::now();
+                    retransmitted = true;
+                },
+                Err(err) => return Err(err),
+                Ok(_) => {}
             }
         }
+        if !retransmitted{
+            self.rtt.sample(sent_at.elapsed());
+        }
         println!("Done connection {} {}", self.sequence, self.ack);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Connection{
+    fn drop(&mut self){
+        // don't leave a pending ack behind
+        if self.ack_deadline.is_some(){
+            let ack = self.new_ack();
+            let _ = self.queue(ack);
+        }
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::net::UdpSocket;
+    use crate::protocol::ecn;
+    use crate::protocol::packets::{encode_datagram, Encoding, MAX_DATAGRAM_SIZE};
+    use crate::protocol::stats::Stats;
+    use crate::protocol::congestion::MIN_WINDOW;
+
+    /**
+     * A transport on a loopback socket, reading the codepoints of the datagrams received
+     */
+    fn transport() -> (Arc<UdpSocket>, Arc<Transport>){
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        ecn::enable(&socket).unwrap();
+        let transport = Transport::new(socket.clone(), Arc::new(Stats::default()), Encoding::default(), None, true);
+        (socket, Arc::new(transport))
+    }
+
+    /**
+     * A connection to peer on a loopback transport, along with the route the test feeds it through
+     */
+    fn connection(sequence : u64, ack : u64, peer : &str) -> (Route, Connection){
+        let (_, transport) = transport();
+        let (route, inbox) = route();
+        let connection = Connection::new(1, sequence, ack, transport, peer.to_string(), inbox, Arc::new(Mutex::new(HashMap::new())));
+        (route, connection)
+    }
+
+    // a peer that is never listened to, the tests hand its packets to the connections directly
+    const PEER : &str = "127.0.0.1:9";
+
+    /**
+     * A sender with count segments of 100 bytes in flight from sequence 1000, along with their content
+     */
+    fn sending(count : usize) -> (Route, Connection, Vec<u8>){
+        let (route, mut sender) = connection(1000, 1, PEER);
+        let content = vec![1; 100 * count];
+        for end in 1..=count{
+            sender.send_packet(&content[..100 * end], 1000).unwrap();
+        }
+        sender.outbox.clear();
+        sender.outbox_len = 0;
+        (route, sender, content)
+    }
+
+    /**
+     * Hand a packet of the peer to the connection, as the receiving thread would
+     */
+    fn deliver(route : &Route, connection : &mut Connection, packet : Packet) -> Result<bool, Error>{
+        route.sender.send((packet, PEER.to_string(), Ecn::NotEct)).unwrap();
+        connection.receive(Some(Duration::ZERO))
+    }
+
+    /**
+     * Sequence numbers of the data segments waiting to leave, emptying the outbox
+     */
+    fn queued(connection : &mut Connection) -> Vec<u64>{
+        let sequences = connection.outbox.iter().filter(|packet| packet.get_size() > 0).map(|packet| packet.get_sequence()).collect();
+        connection.outbox.clear();
+        connection.outbox_len = 0;
+        sequences
+    }
+
+    /**
+     * The single frame of the next datagram received, as the receiving thread would forward it
+     */
+    fn receive(transport : &Transport) -> (Packet, String, Ecn){
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let (datagram, src, ecn) = transport.recv_from(&mut buf).unwrap().unwrap();
+        let mut frames : Vec<Packet> = datagram.frames().map(|frame| frame.to_packet()).collect();
+        assert_eq!(frames.len(), 1);
+        (frames.remove(0), src.to_string(), ecn)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn congestion_marks_are_echoed_and_slow_down_the_sender(){
+        let (sender_socket, sender_transport) = transport();
+        let (receiver_socket, receiver_transport) = transport();
+        let receiver_addr = receiver_socket.local_addr().unwrap();
+        let connections : Connections = Arc::new(Mutex::new(HashMap::new()));
+        let (sender_route, sender_inbox) = route();
+        let (receiver_route, receiver_inbox) = route();
+        let mut sender = Connection::new(1, 1000, 5000, sender_transport.clone(), receiver_addr.to_string(),
+            sender_inbox, connections.clone());
+        let mut receiver = Connection::new(1, 5000, 1000, receiver_transport.clone(), sender_socket.local_addr().unwrap().to_string(),
+            receiver_inbox, connections.clone());
+        let content = vec![1; 200];
+
+        // a data segment, marked on the way by a congested router
+        sender.send_packet(&content, 1000).unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let len = encode_datagram(&sender.outbox, 1, Encoding::default(), &mut buf).unwrap();
+        sender.outbox.clear();
+        sender.outbox_len = 0;
+        ecn::send_to(&sender_socket, &buf[..len], receiver_addr, Ecn::Ce).unwrap();
+        let (data, from, ecn) = receive(&receiver_transport);
+        assert_eq!(ecn, Ecn::Ce);
+        receiver_route.sender.send((data, from, ecn)).unwrap();
+        assert!(receiver.receive(Some(Duration::ZERO)).unwrap());
+
+        // echoed right away, in an ack that isn't ECN capable
+        receiver.flush().unwrap();
+        let (ack, from, ecn) = receive(&sender_transport);
+        assert!(ack.is_pure_ack());
+        assert_eq!(ce_count_of(ack.get_options()), Some(1));
+        assert_eq!(ecn, Ecn::NotEct);
+
+        // the sender slows down as on a loss
+        let cwnd = sender.congestion.cwnd();
+        sender_route.sender.send((ack, from, ecn)).unwrap();
+        assert!(!sender.receive(Some(Duration::ZERO)).unwrap());
+        assert!(sender.congestion.cwnd() < cwnd);
+
+        // the next data segment leaves ECN capable
+        sender.send_packet(&content, 1200).unwrap();
+        sender.flush().unwrap();
+        let (data, _, ecn) = receive(&receiver_transport);
+        assert_eq!(data.get_sequence(), 1200);
+        assert_eq!(ecn, Ecn::Ect0);
+    }
+
+    #[test]
+    fn segments_far_past_the_ack_are_not_buffered(){
+        let (_, mut receiver) = connection(1, 1000, "127.0.0.1:9");
+        receiver.buffer_out_of_order(Packet::new_data(vec![1; 10], 1000 + RECEIVE_BUFFER, 1));
+        // a sequence number close to the end of the space must not overflow
+        receiver.buffer_out_of_order(Packet::new_data(vec![1; 10], u64::MAX - 5, 1));
+        assert!(receiver.out_of_order.is_empty());
+        receiver.buffer_out_of_order(Packet::new_data(vec![1; 10], 1000 + RECEIVE_BUFFER - 10, 1));
+        assert_eq!(receiver.out_of_order.len(), 1);
+    }
+
+    #[test]
+    fn acks_inside_a_segment_stop_at_its_start(){
+        let peer = "127.0.0.1:9";
+        let (route, mut sender) = connection(1000, 1, peer);
+        let content = vec![1; 100];
+        for init_sequence in [1000, 1100, 1200]{
+            sender.send_packet(&content, init_sequence).unwrap();
+        }
+        sender.outbox.clear();
+        let ack = Packet::new_ack(1, 1150).with_option(PacketOption::Sack(vec![(1200, 1300)]));
+        route.sender.send((ack, peer.to_string(), Ecn::NotEct)).unwrap();
+        assert!(!sender.receive(Some(Duration::ZERO)).unwrap());
+        assert_eq!(sender.sequence, 1100);
+        assert_eq!(sender.in_flight, 200);
+        assert!(sender.unacked[&1200].sacked);
+        assert_eq!(sender.pipe(), 100);
+    }
+
+    #[test]
+    fn the_window_follows_the_data_the_application_didnt_read(){
+        let (peer_socket, peer_transport) = transport();
+        let (route, mut receiver) = connection(1, 1000, &peer_socket.local_addr().unwrap().to_string());
+        receiver.set_receive_buffer(1000);
+        route.sender.send((Packet::new_data(vec![1; 600], 1000, 1), peer_socket.local_addr().unwrap().to_string(), Ecn::NotEct)).unwrap();
+        assert!(receiver.receive(Some(Duration::ZERO)).unwrap());
+
+        // the delayed ack tells what is left
+        let (ack, _) = route.ack.lock().unwrap().packet().unwrap();
+        assert_eq!(window_of(ack.get_options()), Some(400));
+        // nothing past the window is kept
+        receiver.buffer_out_of_order(Packet::new_data(vec![1; 10], 1600 + 400, 1));
+        assert!(receiver.out_of_order.is_empty());
+
+        // the application reads, the peer learns right away that the window opened again
+        assert_eq!(receiver.recv().unwrap().len(), 600);
+        let (ack, _, _) = receive(&peer_transport);
+        assert_eq!(ack.get_acked(), 1600);
+        assert_eq!(window_of(ack.get_options()), Some(1000));
+    }
+
+    #[test]
+    fn the_sender_stays_within_the_window_of_the_peer(){
+        let peer = "127.0.0.1:9";
+        let (route, mut sender) = connection(1000, 1, peer);
+        let ack = Packet::new_ack(1, 1000).with_option(PacketOption::Window(300));
+        route.sender.send((ack, peer.to_string(), Ecn::NotEct)).unwrap();
+        assert!(!sender.receive(Some(Duration::ZERO)).unwrap());
+        assert!(sender.congestion.cwnd() > 300);
+        assert_eq!(sender.send_window(), 300);
+
+        let content = vec![1; 1000];
+        sender.send_packet(&content, 1000).unwrap();
+        assert_eq!(sender.in_flight, 300);
+        assert_eq!(sender.send_window(), 0);
+
+        // acked without opening the window any further, nothing more may leave
+        let ack = Packet::new_ack(1, 1300).with_option(PacketOption::Window(0));
+        route.sender.send((ack, peer.to_string(), Ecn::NotEct)).unwrap();
+        assert!(!sender.receive(Some(Duration::ZERO)).unwrap());
+        assert_eq!(sender.send_window(), 0);
+
+        // the window update
+        let ack = Packet::new_ack(1, 1300).with_option(PacketOption::Window(500));
+        route.sender.send((ack, peer.to_string(), Ecn::NotEct)).unwrap();
+        assert!(!sender.receive(Some(Duration::ZERO)).unwrap());
+        assert_eq!(sender.send_window(), 500);
+    }
+
+    #[test]
+    fn only_the_segment_whose_timer_expired_is_sent_again(){
+        let (_, mut sender, content) = sending(4);
+        let rto = sender.rtt.rto();
+        sender.unacked.get_mut(&1000).unwrap().sent_at -= rto;
+        assert_eq!(sender.retransmit_lost(&content, 1000).unwrap(), Duration::ZERO);
+        assert_eq!(queued(&mut sender), vec![1000]);
+        assert!(sender.unacked[&1000].retransmitted);
+        assert!(sender.unacked.values().skip(1).all(|segment| !segment.retransmitted && !segment.lost));
+        // the timer backs off, the window starts over from a segment
+        assert_eq!(sender.timeouts, 1);
+        assert_eq!(sender.rtt.rto(), 2 * rto);
+        assert_eq!(sender.congestion.cwnd(), MAX_SIZE as u64);
+    }
+
+    #[test]
+    fn holes_below_sacked_segments_are_sent_again(){
+        let (route, mut sender, content) = sending(5);
+        let ack = Packet::new_ack(1, 1000).with_option(PacketOption::Sack(vec![(1100, 1400)]));
+        assert!(!deliver(&route, &mut sender, ack).unwrap());
+        assert!([1100, 1200, 1300].iter().all(|sequence| sender.unacked[sequence].sacked));
+        assert!(!sender.unacked[&1000].sacked && !sender.unacked[&1400].sacked);
+        // the sacked segments left the network
+        assert_eq!(sender.pipe(), 200);
+
+        // three segments sacked above the hole, it is lost, the last one may still come
+        sender.retransmit_lost(&content, 1000).unwrap();
+        assert_eq!(queued(&mut sender), vec![1000]);
+        assert!(!sender.unacked[&1400].lost);
+        assert_eq!(sender.recovery, Some(1500));
+        assert_eq!(sender.timeouts, 0);
+    }
+
+    #[test]
+    fn three_duplicate_acks_retransmit_then_partial_acks_point_at_the_next_hole(){
+        let (route, mut sender, content) = sending(5);
+        for _ in 0..2{
+            assert!(!deliver(&route, &mut sender, Packet::new_ack(1, 1000)).unwrap());
+        }
+        assert_eq!(sender.recovery, None);
+        assert!(!sender.unacked[&1000].lost);
+
+        // fast retransmit, the window shrinks once
+        assert!(!deliver(&route, &mut sender, Packet::new_ack(1, 1000)).unwrap());
+        assert_eq!(sender.recovery, Some(1500));
+        assert!(sender.unacked[&1000].lost);
+        assert_eq!(sender.congestion.cwnd(), MIN_WINDOW);
+        sender.retransmit_lost(&content, 1000).unwrap();
+        assert_eq!(queued(&mut sender), vec![1000]);
+
+        // a partial ack, the segment following it was lost too
+        assert!(!deliver(&route, &mut sender, Packet::new_ack(1, 1200)).unwrap());
+        assert_eq!(sender.sequence, 1200);
+        assert!(sender.unacked[&1200].lost);
+        assert!(!sender.unacked[&1300].lost);
+        sender.retransmit_lost(&content, 1000).unwrap();
+        assert_eq!(queued(&mut sender), vec![1200]);
+        assert_eq!(sender.congestion.cwnd(), MIN_WINDOW);
+
+        // everything sent before the loss is acked, recovery is over
+        assert!(!deliver(&route, &mut sender, Packet::new_ack(1, 1500)).unwrap());
+        assert_eq!(sender.recovery, None);
+        assert_eq!(sender.in_flight, 0);
+        assert!(sender.unacked.is_empty());
+    }
+
+    #[test]
+    fn segments_late_behind_a_delivered_one_are_lost(){
+        let (route, mut sender, content) = sending(3);
+        sender.rtt.sample(Duration::from_millis(20));
+        // a quarter of the min RTT
+        assert_eq!(sender.reorder_window(), Duration::from_millis(5));
+        sender.unacked.get_mut(&1000).unwrap().sent_at -= Duration::from_millis(50);
+
+        // the second segment is delivered, the first one is late by more than the window
+        let ack = Packet::new_ack(1, 1000).with_option(PacketOption::Sack(vec![(1100, 1200)]));
+        assert!(!deliver(&route, &mut sender, ack).unwrap());
+        assert_eq!(sender.rack.as_ref().unwrap().end, 1200);
+        sender.retransmit_lost(&content, 1000).unwrap();
+        assert_eq!(queued(&mut sender), vec![1000]);
+        // the third one was sent after the delivered one, it isn't late
+        assert!(!sender.unacked[&1200].lost);
+        assert_eq!(sender.recovery, Some(1300));
+
+        // delivered faster than the min RTT, the ack is for the original transmission
+        let latest = sender.rack.as_ref().unwrap().sent_at;
+        sender.rack_update(Instant::now(), 1100, true);
+        assert_eq!(sender.rack.as_ref().unwrap().sent_at, latest);
+    }
+
+    #[test]
+    fn the_next_timeout_is_the_earliest_deadline(){
+        let (_, mut sender, _) = sending(2);
+        sender.rtt.sample(Duration::from_millis(20));
+        assert_eq!(sender.rtt.rto(), Duration::from_millis(60));
+
+        // the tail loss probe, two round trips away
+        sender.arm_tail_loss_probe();
+        let timeout = sender.next_timeout();
+        assert!(timeout <= Duration::from_millis(40) && timeout > Duration::from_millis(35), "{:?}", timeout);
+
+        // the first segment is late behind a delivered one, it is lost once the reordering window elapsed
+        let sent_at = sender.unacked[&1100].sent_at;
+        sender.unacked.get_mut(&1100).unwrap().sacked = true;
+        sender.rack_update(sent_at, 1200, false);
+        let rtt = sender.rack.as_ref().unwrap().rtt;
+        let deadline = sender.unacked[&1000].sent_at + rtt + sender.reorder_window();
+        let timeout = sender.next_timeout();
+        assert!(timeout <= deadline.saturating_duration_since(Instant::now()) + Duration::from_millis(1), "{:?}", timeout);
+        assert!(timeout < Duration::from_millis(10));
+
+        // a lone segment may wait for a delayed ack, the probe waits longer
+        sender.unacked.remove(&1100);
+        sender.rack = None;
+        sender.arm_tail_loss_probe();
+        let timeout = sender.next_timeout();
+        assert!(timeout <= Duration::from_millis(50) && timeout > Duration::from_millis(45), "{:?}", timeout);
+    }
+
+    #[test]
+    fn the_tail_loss_probe_sends_the_last_segment_again(){
+        let (_, mut sender, content) = sending(3);
+        sender.rtt.sample(Duration::from_millis(20));
+        sender.arm_tail_loss_probe();
+        sender.retransmit_lost(&content, 1000).unwrap();
+        assert!(queued(&mut sender).is_empty());
+
+        sender.tlp_deadline = Some(Instant::now());
+        sender.retransmit_lost(&content, 1000).unwrap();
+        assert_eq!(queued(&mut sender), vec![1200]);
+        assert!(sender.unacked[&1200].retransmitted);
+        // a single probe until an ack comes
+        assert_eq!(sender.tlp_deadline, None);
+        assert_eq!(sender.recovery, None);
+    }
+}
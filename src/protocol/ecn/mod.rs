@@ -0,0 +1,234 @@
+use std::io::Error;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::protocol::packets::options::PacketOption;
+
+/**
+ * ECN codepoint of a datagram, the two low bits of the IPv4 TOS or of the IPv6 traffic class (RFC 3168).
+ * Only the datagrams carrying data are sent ECN capable, the receiver echoes in every ack how many
+ * data segments arrived marked, and the sender slows down as on a loss, at most once per window
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ecn{
+    // the sender doesn't understand the marks, a congested router drops the datagram
+    #[default]
+    NotEct,
+    // the sender understands the marks, a congested router marks the datagram instead of dropping it
+    Ect1,
+    Ect0,
+    // marked by a congested router
+    Ce
+}
+
+impl Ecn{
+    pub fn from_bits(tos : u8) -> Ecn{
+        match tos & 0b11{
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            0b11 => Ecn::Ce,
+            _ => Ecn::NotEct
+        }
+    }
+
+    pub fn bits(self) -> u8{
+        match self{
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11
+        }
+    }
+}
+
+/**
+ * Number of data segments the peer received marked congestion experienced, if the packet tells
+ */
+pub fn ce_count_of(options : &[PacketOption]) -> Option<u32>{
+    options.iter().find_map(|option| match option{
+        PacketOption::CeCount(count) => Some(*count),
+        _ => None
+    })
+}
+
+#[cfg(target_os = "linux")]
+mod sys{
+    use std::io::{Error, ErrorKind};
+    use std::mem::{size_of, zeroed};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+    use libc::{c_int, c_void, socklen_t};
+
+    use super::Ecn;
+
+    // room for the one control message we send or look for, aligned as the kernel expects
+    type Control = [u64; 8];
+
+    fn setsockopt(socket : &UdpSocket, level : c_int, name : c_int, value : c_int) -> Result<(), Error>{
+        // the value outlives the call and its size is given along
+        let res = unsafe{
+            libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const c_int as *const c_void, size_of::<c_int>() as socklen_t)
+        };
+        if res < 0{
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn enable(socket : &UdpSocket) -> Result<(), Error>{
+        match socket.local_addr()?{
+            SocketAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1),
+            SocketAddr::V6(_) => setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)
+        }
+    }
+
+    fn from_raw(addr : &libc::sockaddr_storage) -> Result<SocketAddr, Error>{
+        match addr.ss_family as c_int{
+            libc::AF_INET => {
+                // the kernel filled it as a sockaddr_in
+                let addr = unsafe{ &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+            },
+            libc::AF_INET6 => {
+                // the kernel filled it as a sockaddr_in6
+                let addr = unsafe{ &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+            },
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unknown address family"))
+        }
+    }
+
+    fn to_raw(addr : &SocketAddr) -> (libc::sockaddr_storage, socklen_t){
+        // all zeroes is a valid value for these plain C structs
+        let mut storage : libc::sockaddr_storage = unsafe{ zeroed() };
+        let len = match addr{
+            SocketAddr::V4(addr) => {
+                let raw = unsafe{ &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                size_of::<libc::sockaddr_in>()
+            },
+            SocketAddr::V6(addr) => {
+                let raw = unsafe{ &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_addr.s6_addr = addr.ip().octets();
+                raw.sin6_flowinfo = addr.flowinfo();
+                raw.sin6_scope_id = addr.scope_id();
+                size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as socklen_t)
+    }
+
+    pub fn recv_from(socket : &UdpSocket, buf : &mut [u8]) -> Result<(usize, SocketAddr, Ecn), Error>{
+        let mut addr : libc::sockaddr_storage = unsafe{ zeroed() };
+        let mut control : Control = [0; 8];
+        let mut iov = libc::iovec{iov_base : buf.as_mut_ptr() as *mut c_void, iov_len : buf.len()};
+        let mut msg : libc::msghdr = unsafe{ zeroed() };
+        msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut c_void;
+        msg.msg_namelen = size_of::<libc::sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = size_of::<Control>() as _;
+        // every pointer in msg refers to a buffer alive until the end of the function
+        let amt = unsafe{ libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if amt < 0{
+            return Err(Error::last_os_error());
+        }
+        let mut ecn = Ecn::NotEct;
+        // the kernel wrote the control messages within msg_controllen
+        unsafe{
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null(){
+                let data = libc::CMSG_DATA(cmsg);
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type){
+                    // a single byte for IPv4, an int for IPv6
+                    (libc::IPPROTO_IP, libc::IP_TOS) => ecn = Ecn::from_bits(*data),
+                    (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => ecn = Ecn::from_bits(ptr::read_unaligned(data as *const c_int) as u8),
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((amt as usize, from_raw(&addr)?, ecn))
+    }
+
+    pub fn send_to(socket : &UdpSocket, buf : &[u8], addr : SocketAddr, ecn : Ecn) -> Result<usize, Error>{
+        let (mut name, namelen) = to_raw(&addr);
+        let mut control : Control = [0; 8];
+        let mut iov = libc::iovec{iov_base : buf.as_ptr() as *mut c_void, iov_len : buf.len()};
+        let mut msg : libc::msghdr = unsafe{ zeroed() };
+        msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut c_void;
+        msg.msg_namelen = namelen;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        let (level, kind) = match addr{
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TOS),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+        };
+        // a single control message carrying the codepoint as an int, well within the buffer
+        let amt = unsafe{
+            msg.msg_controllen = libc::CMSG_SPACE(size_of::<c_int>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = kind;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<c_int>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut c_int, ecn.bits() as c_int);
+            libc::sendmsg(socket.as_raw_fd(), &msg, 0)
+        };
+        if amt < 0{
+            return Err(Error::last_os_error());
+        }
+        Ok(amt as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys{
+    use std::io::Error;
+    use std::net::{SocketAddr, UdpSocket};
+
+    use super::Ecn;
+
+    pub fn enable(_socket : &UdpSocket) -> Result<(), Error>{
+        Ok(())
+    }
+
+    pub fn recv_from(socket : &UdpSocket, buf : &mut [u8]) -> Result<(usize, SocketAddr, Ecn), Error>{
+        let (amt, src) = socket.recv_from(buf)?;
+        Ok((amt, src, Ecn::NotEct))
+    }
+
+    pub fn send_to(socket : &UdpSocket, buf : &[u8], addr : SocketAddr, _ecn : Ecn) -> Result<usize, Error>{
+        socket.send_to(buf, addr)
+    }
+}
+
+/**
+ * Have the kernel tell the codepoint of the datagrams received on the socket,
+ * only on Linux, elsewhere nothing is seen
+ */
+pub fn enable(socket : &UdpSocket) -> Result<(), Error>{
+    sys::enable(socket)
+}
+
+/**
+ * Receive a datagram along with its codepoint, NotEct when the kernel doesn't tell
+ */
+pub fn recv_from(socket : &UdpSocket, buf : &mut [u8]) -> Result<(usize, SocketAddr, Ecn), Error>{
+    sys::recv_from(socket, buf)
+}
+
+/**
+ * Send a datagram with the given codepoint, the socket sends the others not ECN capable.
+ * Only on Linux, elsewhere the codepoint is ignored
+ */
+pub fn send_to(socket : &UdpSocket, buf : &[u8], addr : SocketAddr, ecn : Ecn) -> Result<usize, Error>{
+    sys::send_to(socket, buf, addr, ecn)
+}
@@ -1,6 +1,7 @@
 use std::sync::mpsc::{channel, Receiver};
 use std::sync::{Arc, Mutex};
-use std::{collections::HashMap, net::UdpSocket, sync::mpsc::Sender, thread, time::Duration};
+use std::time::Duration;
+use std::{collections::HashMap, net::UdpSocket, sync::mpsc::Sender, thread, time::Instant};
 use std::hash::{DefaultHasher, Hash, Hasher};
 use rand::{self, random};
 
@@ -8,8 +9,27 @@ use std::io::{Error, ErrorKind};
 
 pub mod packets;
 pub mod connection;
-use connection::Connection;
-use packets::Packet;
+pub mod stats;
+pub mod config;
+pub mod transport;
+pub mod trace;
+pub mod rtt;
+pub mod timestamps;
+pub mod congestion;
+pub mod pacer;
+pub mod ecn;
+use connection::{route, Connection, Connections, Inbox};
+use stats::Stats;
+use config::Config;
+use transport::Transport;
+use trace::Tracer;
+use rtt::RttEstimator;
+use timestamps::{timestamp_of, TimestampClock};
+use packets::options::PacketOption;
+use packets::{Packet, MAX_DATAGRAM_SIZE};
+
+// how often the receiving thread wakes up to send the delayed acks due, when no datagram comes
+const TICK : Duration = Duration::from_millis(5);
 
 fn hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
@@ -25,8 +45,14 @@ pub struct Protocol{
     sender : Arc<Sender<Connection>>,
     // a handle used to stop the receiving thread
     handle : Option<Sender<()>>,
-    // map of <host, Sender> used to send packets to the corresponding connection
-    connections : Arc<Mutex<HashMap<String, Sender<Packet>>>>
+    // map of <connection id, Sender> used to send packets to the corresponding connection
+    connections : Connections,
+    // counters about the traffic seen by this socket
+    stats : Arc<Stats>,
+    // encodes, sends and receives the datagrams on the socket
+    transport : Arc<Transport>,
+    // settings given when creating the socket
+    config : Config
 }
 
 
@@ -36,113 +62,241 @@ impl Protocol{
      * Create a new socket
      */
     pub fn new(addr : &str) -> Result<Protocol, std::io::Error>{
+        Protocol::with_config(addr, Config::default())
+    }
+
+    /**
+     * Create a new socket with some specific settings
+     */
+    pub fn with_config(addr : &str, config : Config) -> Result<Protocol, std::io::Error>{
         let socket = Arc::new(UdpSocket::bind(addr)?);
+        socket.set_read_timeout(Some(TICK))?;
+        if config.ecn{
+            ecn::enable(&socket)?;
+        }
         let (sender, listeners) = channel();
         let sender = Arc::new(sender);
-        Ok(Protocol{socket, listeners, sender, handle : None, connections : Arc::new(Mutex::new(HashMap::new()))})
+        let stats = Arc::new(Stats::default());
+        let tracer = match &config.trace{
+            Some(path) => Some(Tracer::create(path)?),
+            None => None
+        };
+        let transport = Arc::new(Transport::new(socket.clone(), stats.clone(), config.encoding, tracer, config.ecn));
+        Ok(Protocol{socket, listeners, sender, handle : None, connections : Arc::new(Mutex::new(HashMap::new())), stats, transport, config})
+    }
+
+    /**
+     * Counters about the traffic seen by this socket
+     */
+    pub fn stats(&self) -> Arc<Stats>{
+        self.stats.clone()
     }
 
     /**
      * Connect this socket to another host, used for client applications
      */
     pub fn connect(&mut self, addr : String) -> Result<Connection, std::io::Error>{
-        let mut rto = Duration::from_millis(100);
+        self.receive_loop();
+        // the first measure is taken on the handshake
+        let mut rtt = RttEstimator::new();
+        let clock = TimestampClock::new();
         let max_transmit = 5;
         let mut transmit = 0;
+        let seq : u16 = random(); // random between 0 and 64000
+        let seq = seq as u64;
+        let (mut id, mut inbox) = self.reserve_id();
         loop {
             transmit += 1;
-            let seq : u16 = random(); // random between 0 and 64000
-            let seq = seq as u64;
-            let syn = Packet::new_syn(seq);
-            self.socket.send_to(&syn.to_bytes(), addr.clone())?;
+            let mut syn = Packet::new_syn(seq);
+            if self.config.timestamps{
+                syn = syn.with_option(PacketOption::Timestamp{value : clock.now(), echo : 0});
+            }
+            self.transport.send_to(&syn, id, &addr)?;
+            let sent_at = Instant::now();
             println!("Sent syn");
-    
-            let mut buf = [0; 2560];
-            self.socket.set_read_timeout(Some(rto))?;
-            let amt = self.socket.recv(&mut buf);
-            self.socket.set_read_timeout(None)?;
 
-            if let Err(_) = amt{
+            let synack = inbox.receiver.recv_timeout(rtt.rto());
+            if synack.is_err(){
+                if let Some(version) = self.transport.peer_version(&addr){
+                    self.connections.lock().unwrap().remove(&id);
+                    return Err(Error::new(ErrorKind::Unsupported, format!("Peer runs version {} of the protocol", version)))
+                }
                 // exponential backoff
                 if transmit > max_transmit{
+                    self.connections.lock().unwrap().remove(&id);
                     return Err(Error::new(ErrorKind::ConnectionAborted, "Failed to connect"))
                 }
-                rto *= 2;
+                rtt.backoff();
                 //println!("Syn ack not received, retrying...");
                 continue;
             }
-            let amt = amt.unwrap();
             //println!("Received synack");
-            let synack = Packet::from_bytes(buf[..amt].to_vec());
+            let (synack, from, _) = synack.unwrap();
+            if synack.is_reset() && synack.get_sequence() == seq + 1{
+                // another client got the id first, the server refuses ours
+                if transmit > max_transmit{
+                    return Err(Error::new(ErrorKind::ConnectionRefused, "Failed to connect"))
+                }
+                (id, inbox) = self.reserve_id();
+                continue;
+            }
             if !synack.is_syn() || !synack.is_ack() || seq + 1 != synack.get_acked(){
                 continue;
             }
 
-            let (tx, rx) = channel();
+            // the peer agreed on timestamps if it echoes ours
+            let timestamp = timestamp_of(synack.get_options()).filter(|_| self.config.timestamps);
+            match timestamp{
+                // only the first syn can be told apart from its retransmissions (Karn's rule)
+                _ if transmit == 1 => rtt.sample(sent_at.elapsed()),
+                // otherwise the echo tells which syn is acked, to the millisecond
+                Some((_, echo)) => rtt.sample(clock.since(echo)),
+                None => {}
+            }
+            let mut connection = Connection::new(id, seq+1, synack.get_sequence()+1, self.transport.clone(), from.clone(), inbox, self.connections.clone())
+                .with_rtt(rtt)
+                .with_congestion(self.config.congestion.controller());
+            connection.set_pacing_rate(self.config.pacing_rate);
+            connection.set_receive_buffer(self.config.receive_buffer);
 
-            let connection = Connection::new(seq+1, synack.get_sequence()+1, self.socket.clone(), addr.clone(), rx, self.connections.clone());
-     
-            let ack = Packet::new_ack(seq+1, synack.get_sequence()+1);
-            self.socket.send_to(&ack.to_bytes(), addr.clone())?;
-            self.receive_loop(Some((addr.clone(), tx)));
+            let receive_buffer = u64::min(self.config.receive_buffer, u32::MAX as u64) as u32;
+            let mut ack = Packet::new_ack(seq+1, synack.get_sequence()+1).with_option(PacketOption::Window(receive_buffer));
+            if let Some((value, _)) = timestamp{
+                ack = ack.with_option(PacketOption::Timestamp{value : clock.now(), echo : value});
+                connection = connection.with_timestamps(clock, value);
+            }
+            self.transport.send_to(&ack, id, &from)?;
             return Ok(connection);
         }
     }
 
-    fn receive_loop(&mut self, conn : Option<(String, Sender<Packet>)>){
+    /**
+     * Pick the id under which both hosts will know a new connection, 0 is never used
+     */
+    fn reserve_id(&self) -> (u32, Inbox){
+        loop{
+            let id : u32 = random();
+            let mut map = self.connections.lock().unwrap();
+            if id != 0 && !map.contains_key(&id){
+                let (route, inbox) = route();
+                map.insert(id, route);
+                return (id, inbox);
+            }
+        }
+    }
+
+    fn receive_loop(&mut self){
         if self.handle.is_some(){
             return;
         }
 
         let (tx, finished) = channel();
         self.handle = Some(tx);
-        let sock = self.socket.clone();
+        let transport = self.transport.clone();
         let sender = self.sender.clone();
+        let use_timestamps = self.config.timestamps;
+        let congestion = self.config.congestion;
+        let pacing_rate = self.config.pacing_rate;
+        let receive_buffer = self.config.receive_buffer;
 
         let connections = self.connections.clone();
 
         thread::spawn(move ||{
-            if let Some((addr, sender)) = conn{
-                connections.clone().lock().unwrap().insert(addr, sender);
-            }
+            // datagrams are parsed in place, the frames served to a connection are copied out of this buffer
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            // syn that opened each incoming connection, by id, with its address, its sequence number
+            // and whether the handshake is still going on
+            let mut syns = HashMap::new();
+            let mut last_tick = Instant::now();
             loop {
                 if finished.try_recv().is_ok(){
                     break;
                 }
-                let mut buf = [0; 2560];
-                let res = sock.recv_from(&mut buf);
-                if res.is_err(){
-                    continue;
+                if last_tick.elapsed() >= TICK{
+                    // the applications may be busy elsewhere, send the delayed acks for them
+                    last_tick = Instant::now();
+                    let routes : Vec<_> = connections.lock().unwrap().iter().map(|(id, route)| (*id, route.ack.clone())).collect();
+                    for (id, ack) in routes{
+                        let due = ack.lock().unwrap().due(last_tick);
+                        if let Some((ack, addr)) = due{
+                            let _ = transport.send_to(&ack, id, &addr);
+                        }
+                    }
                 }
-                let (amt, src) = res.unwrap();
-                let received = Packet::from_bytes(buf[..amt].to_vec());
-    
+                let (received, src, ecn) = match transport.recv_from(&mut buf){
+                    Ok(Some(res)) => res,
+                    // corrupted, malformed or not one of ours, drop it before it gets acked,
+                    // or nothing came in time
+                    _ => continue
+                };
 
+                let id = received.get_connection_id();
                 let addr = src.to_string();
-                if received.is_syn(){
-                    // begin handshake by sending syn-ack
-                    let seq = hash(&src); // use an hash to avoid syn flooding
-                    let (tx, rx) = channel();
-                    let connection = Connection::new(seq, received.get_sequence(), sock.clone(), addr.clone(), rx, connections.clone());
-                    let _ = sender.send(connection);
-                    connections.clone().lock().unwrap().insert(addr, tx);
-                    continue;
-                }
-
-                if !connections.clone().lock().unwrap().contains_key(&addr){
-                    continue;
-                }else{
-                    // data packet/reset, serve to correct connection
-                    let arc = connections.clone();
-                    let locked = arc.lock().unwrap();
-                    let conn = locked.get(&addr).unwrap();
-                    if received.is_reset(){
-                        // remove directly without waiting for an ack
-                        let _ = conn.send(received);
-                        connections.lock().unwrap().remove(&addr);
-                    }else{
-                        // data/fin packet, send it to the connection
-                        let _ = conn.send(received);
+                // serve the frames to the connection with this id, wherever they come from
+                let mut conn = connections.lock().unwrap().get(&id).cloned();
+                for frame in received.frames(){
+                    // a late syn-ack belongs to an existing connection, don't mistake it for a new one
+                    if frame.is_syn() && !frame.is_ack() && id != 0{
+                        if conn.is_some(){
+                            match syns.get(&id){
+                                // the same syn sent again, it only replaces a connection still in the handshake
+                                Some((from, seq, handshake)) if *from == src && *seq == frame.get_sequence() => {
+                                    if !handshake{
+                                        // a late duplicate, the connection is established already
+                                        continue;
+                                    }
+                                },
+                                // another client picked the same id, it picks another one on the reset,
+                                // which carries the sequence number a syn-ack would ack so that it can tell
+                                _ => {
+                                    let _ = transport.send_to(&Packet::new_reset(frame.get_sequence() + 1), id, &addr);
+                                    continue;
+                                }
+                            }
+                        }
+                        // begin handshake by sending syn-ack
+                        // use an hash to avoid syn flooding, kept on 32 bits so that
+                        // sequence numbers stay short with the compact encoding
+                        let seq = hash(&(src, id)) as u32 as u64;
+                        let (route, inbox) = route();
+                        let mut connection = Connection::new(id, seq, frame.get_sequence(), transport.clone(), addr.clone(), inbox, connections.clone())
+                            .with_congestion(congestion.controller());
+                        connection.set_pacing_rate(pacing_rate);
+                        connection.set_receive_buffer(receive_buffer);
+                        if let Some((value, _)) = timestamp_of(&frame.get_options()).filter(|_| use_timestamps){
+                            // the peer offered timestamps, the syn-ack will carry ours
+                            connection = connection.with_timestamps(TimestampClock::new(), value);
+                        }
+                        // a retransmitted syn replaces the previous connection with the same id
+                        let mut map = connections.lock().unwrap();
+                        map.insert(id, route.clone());
+                        syns.retain(|id, _| map.contains_key(id));
+                        syns.insert(id, (src, frame.get_sequence(), true));
+                        drop(map);
+                        let _ = sender.send(connection);
+                        conn = Some(route);
+                        continue;
+                    }
+                    if let Some(route) = &conn{
+                        // anything else from the peer tells it got the syn-ack
+                        if let Some((from, _, handshake)) = syns.get_mut(&id){
+                            if *from == src{
+                                *handshake = false;
+                            }
+                        }
+                        let packet = frame.to_packet();
+                        // keepalive probe, or an ack overtaken by data, tell the peer where we are
+                        // even if the application isn't waiting on the connection
+                        let answer = route.ack.lock().unwrap().answer(&packet);
+                        if let Some((ack, to)) = answer{
+                            let _ = transport.send_to(&ack, id, &to);
+                        }
+                        let _ = route.sender.send((packet, addr.clone(), ecn));
+                        if frame.is_reset(){
+                            // remove directly without waiting for an ack
+                            connections.lock().unwrap().remove(&id);
+                            conn = None;
+                        }
                     }
                 }
             }
@@ -153,7 +307,7 @@ impl Protocol{
      * Listen for new connection, and return when a connection has been established
      */
     pub fn listen(&mut self) -> Result<Connection, Error>{
-        self.receive_loop(None);
+        self.receive_loop();
         match self.listeners.recv(){
             Ok(conn) => Ok(conn),
             Err(_) => Err(Error::new(ErrorKind::NotConnected, ""))
@@ -168,4 +322,57 @@ impl Protocol{
             let _ = handle.send(());
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use packets::Encoding;
+
+    /**
+     * A bare client, sending frames by hand
+     */
+    fn client() -> Transport{
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        Transport::new(socket, Arc::new(Stats::default()), Encoding::default(), None, false)
+    }
+
+    /**
+     * The first frame of the next datagram the client receives, none if nothing comes
+     */
+    fn receive(client : &Transport) -> Option<Packet>{
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let (datagram, _, _) = client.recv_from(&mut buf).ok()??;
+        let frame = datagram.frames().next()?;
+        Some(frame.to_packet())
+    }
+
+    #[test]
+    fn a_syn_reusing_an_id_is_reset(){
+        let mut server = Protocol::new("127.0.0.1:0").unwrap();
+        server.receive_loop();
+        let server_addr = server.socket.local_addr().unwrap().to_string();
+        let (first, second) = (client(), client());
+        let wait = Duration::from_secs(1);
+
+        first.send_to(&Packet::new_syn(100), 7, &server_addr).unwrap();
+        let _connection = server.listeners.recv_timeout(wait).unwrap();
+        // the same syn sent again replaces the connection still in the handshake
+        first.send_to(&Packet::new_syn(100), 7, &server_addr).unwrap();
+        let _connection = server.listeners.recv_timeout(wait).unwrap();
+
+        // another client picked the same id, it is told to pick another one
+        second.send_to(&Packet::new_syn(200), 7, &server_addr).unwrap();
+        let reset = receive(&second).unwrap();
+        assert!(reset.is_reset());
+        assert_eq!(reset.get_sequence(), 201);
+        assert!(server.listeners.try_recv().is_err());
+
+        // the handshake is over, a late duplicate of the syn changes nothing
+        first.send_to(&Packet::new_ack(101, 1), 7, &server_addr).unwrap();
+        first.send_to(&Packet::new_syn(100), 7, &server_addr).unwrap();
+        assert!(receive(&first).is_none());
+        assert!(server.listeners.try_recv().is_err());
+        server.stop();
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use crate::protocol::connection::MAX_SIZE;
+
+// bytes that may leave back to back, at least a pair of segments as for delayed acks
+pub const BURST : u64 = 2 * MAX_SIZE as u64;
+// credit kept for a wait that lasted longer than asked, so that a late wakeup doesn't lower the rate
+pub const BURST_INTERVAL : Duration = Duration::from_millis(5);
+// shorter waits cost more than they save, the segment leaves on credit and the next wait is longer
+pub const GRANULARITY : Duration = Duration::from_micros(500);
+
+/**
+ * Token bucket spacing the segments so that they leave at a given rate
+ * instead of in bursts that overflow the small buffers on the path
+ */
+#[derive(Debug, Clone)]
+pub struct Pacer{
+    // bytes that may leave right away, negative once a segment left on credit
+    tokens : f64,
+    // last time tokens were added
+    refilled_at : Instant
+}
+
+impl Default for Pacer{
+    fn default() -> Self{
+        Pacer::new()
+    }
+}
+
+impl Pacer{
+    pub fn new() -> Pacer{
+        Pacer{tokens : BURST as f64, refilled_at : Instant::now()}
+    }
+
+    /**
+     * Add the tokens earned since the last refill at rate bytes per second,
+     * without pacing the bucket stays full
+     */
+    fn refill(&mut self, rate : Option<f64>){
+        let now = Instant::now();
+        self.tokens = match rate{
+            Some(rate) => {
+                let capacity = f64::max(BURST as f64, rate * BURST_INTERVAL.as_secs_f64());
+                f64::min(self.tokens + rate * now.duration_since(self.refilled_at).as_secs_f64(), capacity)
+            },
+            None => BURST as f64
+        };
+        self.refilled_at = now;
+    }
+
+    /**
+     * Time to wait before the next segment may leave, zero if it may leave now
+     */
+    pub fn delay(&mut self, rate : Option<f64>) -> Duration{
+        self.refill(rate);
+        match rate{
+            Some(rate) if self.tokens < 0.0 => Some(Duration::from_secs_f64(-self.tokens / rate))
+                .filter(|delay| *delay >= GRANULARITY)
+                .unwrap_or_default(),
+            _ => Duration::ZERO
+        }
+    }
+
+    /**
+     * A segment of len bytes left
+     */
+    pub fn spend(&mut self, len : u64, rate : Option<f64>){
+        self.refill(rate);
+        self.tokens -= len as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // 1 MB/s, a segment every 2.56 ms
+    const RATE : Option<f64> = Some(1e6);
+
+    #[test]
+    fn segments_are_spaced_at_the_rate(){
+        let mut pacer = Pacer::new();
+        // a burst leaves back to back
+        pacer.spend(BURST, RATE);
+        assert_eq!(pacer.delay(RATE), Duration::ZERO);
+        pacer.spend(MAX_SIZE as u64, RATE);
+        let delay = pacer.delay(RATE);
+        assert!(delay <= Duration::from_micros(2560) && delay >= Duration::from_micros(2400), "{:?}", delay);
+    }
+
+    #[test]
+    fn nothing_waits_without_a_rate(){
+        let mut pacer = Pacer::new();
+        pacer.spend(10 * BURST, None);
+        assert_eq!(pacer.delay(None), Duration::ZERO);
+        assert_eq!(pacer.tokens, BURST as f64);
+    }
+
+    #[test]
+    fn waits_shorter_than_the_granularity_are_skipped(){
+        let mut pacer = Pacer::new();
+        // 400 bytes of credit, 0.4 ms
+        pacer.spend(BURST + 400, RATE);
+        assert_eq!(pacer.delay(RATE), Duration::ZERO);
+        // the credit adds up to the next wait
+        pacer.spend(600, RATE);
+        assert!(pacer.delay(RATE) >= GRANULARITY);
+    }
+
+    #[test]
+    fn idle_time_earns_a_burst_at_most(){
+        let idle = Duration::from_secs(1);
+        let mut pacer = Pacer::new();
+        pacer.spend(BURST, RATE);
+        pacer.refilled_at -= idle;
+        assert_eq!(pacer.delay(RATE), Duration::ZERO);
+        assert_eq!(pacer.tokens, BURST as f64);
+        // at a high rate, the credit of a burst interval
+        pacer.refilled_at -= idle;
+        pacer.delay(Some(1e8));
+        assert_eq!(pacer.tokens, 1e8 * BURST_INTERVAL.as_secs_f64());
+    }
+}
@@ -0,0 +1,72 @@
+// reflected polynomial of CRC32C (Castagnoli)
+const POLY : u32 = 0x82F63B78;
+
+const fn make_table() -> [u32; 256]{
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256{
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8{
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const TABLE : [u32; 256] = make_table();
+
+/**
+ * Incremental CRC32C, so that a datagram can be checked without copying it
+ */
+pub struct Crc32c{
+    crc : u32
+}
+
+impl Crc32c{
+    pub fn new() -> Crc32c{
+        Crc32c{crc : !0}
+    }
+
+    pub fn update(&mut self, bytes : &[u8]){
+        for b in bytes{
+            self.crc = TABLE[((self.crc ^ *b as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32{
+        !self.crc
+    }
+}
+
+impl Default for Crc32c{
+    fn default() -> Self{
+        Crc32c::new()
+    }
+}
+
+/**
+ * Compute the CRC32C of some bytes
+ */
+pub fn crc32c(bytes : &[u8]) -> u32{
+    let mut crc = Crc32c::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value(){
+        assert_eq!(crc32c(b"123456789"), 0xE3069283);
+        let mut crc = Crc32c::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xE3069283);
+    }
+}
@@ -0,0 +1,175 @@
+use super::PacketError;
+
+/**
+ * Number of bytes taken by a LEB128 varint
+ */
+pub fn varint_len(mut value : u64) -> usize{
+    let mut len = 1;
+    while value >= 0x80{
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/**
+ * Cursor over a datagram, failing instead of panicking when bytes are missing
+ */
+pub struct Reader<'a>{
+    bytes : &'a [u8],
+    pos : usize
+}
+
+impl<'a> Reader<'a>{
+    pub fn new(bytes : &'a [u8]) -> Reader<'a>{
+        Reader{bytes, pos : 0}
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.pos >= self.bytes.len()
+    }
+
+    pub fn remaining(&self) -> usize{
+        self.bytes.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, len : usize) -> Result<&'a [u8], PacketError>{
+        if len > self.bytes.len() - self.pos{
+            return Err(PacketError::TruncatedHeader);
+        }
+        let bytes = &self.bytes[self.pos..self.pos+len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn take<const N : usize>(&mut self) -> Result<[u8; N], PacketError>{
+        let mut out = [0; N];
+        out.copy_from_slice(self.bytes(N)?);
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, PacketError>{
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, PacketError>{
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, PacketError>{
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, PacketError>{
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    /**
+     * Read a LEB128 varint, 7 bits per byte with the high bit marking continuation
+     */
+    pub fn varint(&mut self) -> Result<u64, PacketError>{
+        let mut value : u64 = 0;
+        let mut shift = 0;
+        loop{
+            let byte = self.u8()?;
+            if shift == 63 && byte > 1{
+                return Err(PacketError::InvalidVarint);
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0{
+                return Ok(value);
+            }
+            shift += 7;
+            if shift > 63{
+                return Err(PacketError::InvalidVarint);
+            }
+        }
+    }
+
+    pub fn rest(&mut self) -> &'a [u8]{
+        let rest = &self.bytes[self.pos..];
+        self.pos = self.bytes.len();
+        rest
+    }
+}
+
+/**
+ * Cursor writing a datagram into a caller provided buffer
+ */
+pub struct Writer<'a>{
+    buf : &'a mut [u8],
+    pos : usize
+}
+
+impl<'a> Writer<'a>{
+    pub fn new(buf : &'a mut [u8]) -> Writer<'a>{
+        Writer{buf, pos : 0}
+    }
+
+    pub fn position(&self) -> usize{
+        self.pos
+    }
+
+    pub fn bytes(&mut self, bytes : &[u8]) -> Result<(), PacketError>{
+        let end = self.pos + bytes.len();
+        if end > self.buf.len(){
+            return Err(PacketError::BufferTooSmall);
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    pub fn u8(&mut self, value : u8) -> Result<(), PacketError>{
+        self.bytes(&[value])
+    }
+
+    pub fn u16(&mut self, value : u16) -> Result<(), PacketError>{
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub fn u32(&mut self, value : u32) -> Result<(), PacketError>{
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub fn u64(&mut self, value : u64) -> Result<(), PacketError>{
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub fn varint(&mut self, mut value : u64) -> Result<(), PacketError>{
+        while value >= 0x80{
+            self.u8((value as u8 & 0x7f) | 0x80)?;
+            value >>= 7;
+        }
+        self.u8(value as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn varints_round_trip(){
+        for value in [0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u64::MAX - 1, u64::MAX]{
+            let mut buf = [0; 10];
+            let mut writer = Writer::new(&mut buf);
+            writer.varint(value).unwrap();
+            let len = writer.position();
+            assert_eq!(len, varint_len(value));
+            let mut reader = Reader::new(&buf[..len]);
+            assert_eq!(reader.varint(), Ok(value));
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn over_long_varints_are_rejected(){
+        // more than 10 bytes
+        assert_eq!(Reader::new(&[0x80; 11]).varint(), Err(PacketError::InvalidVarint));
+        // 10 bytes, but the last one has bits beyond the 64th
+        let mut bytes = [0xff; 10];
+        bytes[9] = 0x02;
+        assert_eq!(Reader::new(&bytes).varint(), Err(PacketError::InvalidVarint));
+    }
+}
@@ -1,4 +1,82 @@
-#[derive(Debug)]
+use std::fmt::{self, Display, Formatter};
+use std::io::{Error, ErrorKind};
+
+pub mod checksum;
+pub mod options;
+mod codec;
+use checksum::{crc32c, Crc32c};
+use codec::{varint_len, Reader, Writer};
+use options::PacketOption;
+
+/**
+ * Wire format of a datagram (version 1), all fixed size integers in network byte order.
+ * A datagram is a header followed by one or more frames, each frame being a packet:
+ *
+ *  0       2       3           7           11
+ *  +-------+-------+-----------+-----------+---------+---------+-----
+ *  | magic |version| checksum  |   conn id | frame 1 | frame 2 | ...
+ *  +-------+-------+-----------+-----------+---------+---------+-----
+ *
+ *  0       1               9               17              25
+ *  +-------+---------------+---------------+---------------+-----------+--------
+ *  | flags |     size      |   sequence    |     acked     | (options) | content
+ *  +-------+---------------+---------------+---------------+-----------+--------
+ *
+ * - magic : always `MAGIC`, used to discard datagrams not meant for this protocol
+ * - version : version of the header layout, peers on another version are rejected
+ * - checksum : CRC32C of the whole datagram, computed with this field set to 0
+ * - conn id : connection the frames belong to, chosen by the host sending the syn.
+ *   Datagrams are demultiplexed on it rather than on the peer address
+ * - flags : 0x80 = compact, 0x10 = options, 0x8 = fin, 0x4 = reset, 0x2 = syn, 0x1 = ack,
+ *   other bits are reserved and must be 0
+ * - size : length of the content, which tells where the next frame starts
+ * - sequence : sequence number of the first byte of content
+ * - acked : next sequence number expected from the peer
+ * - options : only present with the options flag, a 2 bytes length followed
+ *   by that many bytes of options, see the `options` module for their encoding
+ *
+ * When the compact flag is set, size, sequence, acked and the options length are instead
+ * encoded as LEB128 varints, and acked is left out entirely when the ack flag isn't set.
+ * A pure ack frame then only needs a few bytes.
+ *
+ * This way an ack, some data and a fin can travel in the same datagram.
+ *
+ * A datagram of another version is answered with a version reply, only the magic and our version,
+ * so that the peer knows why it gets no other answer. A version reply is never answered,
+ * two hosts on different versions would keep answering each other otherwise.
+ */
+pub const MAGIC : u16 = 0x5250;
+pub const VERSION : u8 = 1;
+pub const DATAGRAM_HEADER_SIZE : usize = 11;
+// size of a frame header with the fixed encoding and no options
+pub const FRAME_HEADER_SIZE : usize = 25;
+const CHECKSUM_OFFSET : usize = 3;
+// size of a version reply, the magic and the version
+pub const VERSION_REPLY_SIZE : usize = 3;
+// largest datagram we may receive, big enough for any UDP payload
+pub const MAX_DATAGRAM_SIZE : usize = 65535;
+
+const FLAG_ACK : u8 = 0x1;
+const FLAG_SYN : u8 = 0x2;
+const FLAG_RESET : u8 = 0x4;
+const FLAG_FIN : u8 = 0x8;
+const FLAG_OPTIONS : u8 = 0x10;
+const FLAG_COMPACT : u8 = 0x80;
+const FLAG_RESERVED : u8 = 0x60;
+
+/**
+ * How the header fields of a packet are laid out on the wire
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding{
+    // fixed 8 bytes fields, easier to read in a hex dump
+    Fixed,
+    // varint fields, smallest on the wire
+    #[default]
+    Compact
+}
+
+#[derive(Debug, Clone)]
 pub struct Packet{
     size : u64,
     content : Vec<u8>,
@@ -7,34 +85,52 @@ pub struct Packet{
     syn : bool,
     ack : bool,
     reset : bool,
-    fin : bool
+    fin : bool,
+    options : Vec<PacketOption>
 }
 
 impl Packet{
-    pub fn new_data(content : Vec<u8>, sequence : u64) -> Packet{
+    /**
+     * Data always carries the current ack, so that the peer doesn't need a separate one
+     */
+    pub fn new_data(content : Vec<u8>, sequence : u64, acked : u64) -> Packet{
         let size = content.len() as u64;
-        let content = Vec::from(content);
-        Packet{size, content, sequence, acked:0, syn:false, ack:false, reset:false, fin : false}
+        Packet{size, content, sequence, acked, syn:false, ack:true, reset:false, fin : false, options : Vec::new()}
     }
 
     pub fn new_ack(sequence : u64, acked : u64) -> Packet{
-        Packet{size:0, content:Vec::new(), sequence, acked, syn:false, ack:true, reset:false, fin : false}
+        Packet{size:0, content:Vec::new(), sequence, acked, syn:false, ack:true, reset:false, fin : false, options : Vec::new()}
     }
 
     pub fn new_synack(sequence : u64, acked : u64) -> Packet{
-        Packet{size:0, content:Vec::new(), sequence, acked, syn:true, ack:true, reset:false, fin : false}
+        Packet{size:0, content:Vec::new(), sequence, acked, syn:true, ack:true, reset:false, fin : false, options : Vec::new()}
     }
 
     pub fn new_syn(sequence : u64) -> Packet{
-        Packet{size:0, content:Vec::new(), sequence, acked:0, syn:true, ack:false, reset:false, fin : false}
+        Packet{size:0, content:Vec::new(), sequence, acked:0, syn:true, ack:false, reset:false, fin : false, options : Vec::new()}
     }
 
     pub fn new_reset(sequence : u64) -> Packet{
-        Packet{size:0, content:Vec::new(), sequence, acked:0, syn:false, ack:false, reset:true, fin : false}
+        Packet{size:0, content:Vec::new(), sequence, acked:0, syn:false, ack:false, reset:true, fin : false, options : Vec::new()}
+    }
+
+    /**
+     * Like data, the fin carries the current ack, so that it acks the fin of the peer if its ack was lost
+     */
+    pub fn new_fin(sequence : u64, acked : u64) -> Packet{
+        Packet{size:0, content:Vec::new(), sequence, acked, syn:false, ack:true, reset:false, fin : true, options : Vec::new()}
     }
 
-    pub fn new_fin(sequence : u64) -> Packet{
-        Packet{size:0, content:Vec::new(), sequence, acked:0, syn:false, ack:false, reset:false, fin : true}
+    /**
+     * Attach an option to this packet
+     */
+    pub fn with_option(mut self, option : PacketOption) -> Packet{
+        self.options.push(option);
+        self
+    }
+
+    pub fn get_options(&self) -> &[PacketOption]{
+        &self.options
     }
 
     pub fn get_content(self) -> Vec<u8>{
@@ -69,39 +165,435 @@ impl Packet{
         self.fin
     }
 
-    pub fn to_bytes(mut self) -> Vec<u8>{
-        let mut vec : Vec<u8> = Vec::new();
+    /**
+     * An ack carrying nothing else, which must never be acked back
+     */
+    pub fn is_pure_ack(&self) -> bool{
+        self.ack && self.size == 0 && !self.syn && !self.fin && !self.reset
+    }
+
+    fn flags(&self, encoding : Encoding) -> u8{
         let mut flags : u8 = 0;
+        if encoding == Encoding::Compact{
+            flags |= FLAG_COMPACT;
+        }
+        if !self.options.is_empty(){
+            flags |= FLAG_OPTIONS;
+        }
         if self.fin{
-            flags = flags | 0x8;
+            flags |= FLAG_FIN;
         }
         if self.reset{
-            flags = flags | 0x4;
+            flags |= FLAG_RESET;
         }
         if self.syn{
-            flags = flags | 0x2;
+            flags |= FLAG_SYN;
         }
         if self.ack{
-            flags = flags | 0x1;
-        }
-        vec.push(flags);
-        vec.append(&mut self.size.to_ne_bytes().to_vec());
-        vec.append(&mut self.sequence.to_ne_bytes().to_vec());
-        vec.append(&mut self.acked.to_ne_bytes().to_vec());
-        vec.append(&mut self.content);
-        vec
-    }
-
-    pub fn from_bytes(bytes : Vec<u8>) -> Packet{
-        let flags = bytes[0];
-        let fin = (flags & 0x8) != 0;
-        let reset = (flags & 0x4) != 0;
-        let syn = (flags & 0x2) != 0;
-        let ack = (flags & 0x1) != 0;
-        let size = u64::from_ne_bytes(bytes[1..9].try_into().unwrap());
-        let sequence = u64::from_ne_bytes(bytes[9..17].try_into().unwrap());
-        let acked = u64::from_ne_bytes(bytes[17..25].try_into().unwrap());
-        let content : Vec<u8> = bytes[25..].to_vec();
-        Packet{size, content, sequence, acked, syn, ack, reset, fin}
-    }
-}
\ No newline at end of file
+            flags |= FLAG_ACK;
+        }
+        flags
+    }
+
+    fn options_len(&self) -> usize{
+        self.options.iter().map(|option| option.encoded_len()).sum()
+    }
+
+    /**
+     * Number of bytes taken by the packet as a frame, the datagram header excluded
+     */
+    pub fn frame_len(&self, encoding : Encoding) -> usize{
+        let options_len = self.options_len();
+        let fields = match encoding{
+            Encoding::Fixed => 24 + if options_len > 0 { 2 } else { 0 },
+            Encoding::Compact => {
+                varint_len(self.size) + varint_len(self.sequence)
+                    + if self.ack { varint_len(self.acked) } else { 0 }
+                    + if options_len > 0 { varint_len(options_len as u64) } else { 0 }
+            }
+        };
+        1 + fields + options_len + self.content.len()
+    }
+
+    /**
+     * Number of bytes taken by the packet once encoded alone in a datagram
+     */
+    pub fn encoded_len(&self, encoding : Encoding) -> usize{
+        DATAGRAM_HEADER_SIZE + self.frame_len(encoding)
+    }
+
+    fn write_frame(&self, encoding : Encoding, writer : &mut Writer) -> Result<(), PacketError>{
+        let options_len = self.options_len();
+        writer.u8(self.flags(encoding))?;
+        match encoding{
+            Encoding::Fixed => {
+                writer.u64(self.size)?;
+                writer.u64(self.sequence)?;
+                writer.u64(self.acked)?;
+                if options_len > 0{
+                    writer.u16(options_len as u16)?;
+                }
+            },
+            Encoding::Compact => {
+                writer.varint(self.size)?;
+                writer.varint(self.sequence)?;
+                if self.ack{
+                    writer.varint(self.acked)?;
+                }
+                if options_len > 0{
+                    writer.varint(options_len as u64)?;
+                }
+            }
+        }
+        for option in &self.options{
+            option.write(writer)?;
+        }
+        writer.bytes(&self.content)
+    }
+
+    /**
+     * Encode the packet alone in a datagram for the connection with the given id,
+     * see `encode_datagram`
+     */
+    pub fn encode_into(&self, connection_id : u32, encoding : Encoding, buf : &mut [u8]) -> Result<usize, PacketError>{
+        encode_datagram(std::slice::from_ref(self), connection_id, encoding, buf)
+    }
+}
+
+/**
+ * Datagram telling a peer on another version which one we run
+ */
+pub fn version_reply() -> [u8; VERSION_REPLY_SIZE]{
+    let magic = MAGIC.to_be_bytes();
+    [magic[0], magic[1], VERSION]
+}
+
+/**
+ * Encode packets as the frames of a single datagram for the connection with the given id
+ * into buf, without allocating anything, and return the number of bytes written
+ */
+pub fn encode_datagram(frames : &[Packet], connection_id : u32, encoding : Encoding, buf : &mut [u8]) -> Result<usize, PacketError>{
+    let mut writer = Writer::new(buf);
+    writer.u16(MAGIC)?;
+    writer.u8(VERSION)?;
+    writer.u32(0)?;
+    writer.u32(connection_id)?;
+    for frame in frames{
+        frame.write_frame(encoding, &mut writer)?;
+    }
+    let len = writer.position();
+    let checksum = crc32c(&buf[..len]);
+    buf[CHECKSUM_OFFSET..CHECKSUM_OFFSET+4].copy_from_slice(&checksum.to_be_bytes());
+    Ok(len)
+}
+
+/**
+ * Datagram parsed in place, its frames are only decoded when iterated over
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct DatagramView<'a>{
+    connection_id : u32,
+    frames : &'a [u8]
+}
+
+impl<'a> DatagramView<'a>{
+    /**
+     * Parse a datagram, never panicking whatever its content.
+     * Datagrams that don't carry our magic, were built for another version
+     * of the header, are corrupted or have a malformed frame are rejected as a whole
+     */
+    pub fn parse(bytes : &'a [u8]) -> Result<DatagramView<'a>, PacketError>{
+        let mut reader = Reader::new(bytes);
+        let magic = reader.u16()?;
+        if magic != MAGIC{
+            return Err(PacketError::BadMagic(magic));
+        }
+        let version = reader.u8()?;
+        if version != VERSION{
+            return Err(PacketError::UnsupportedVersion(version));
+        }
+        let checksum = reader.u32()?;
+        let connection_id = reader.u32()?;
+
+        let mut crc = Crc32c::new();
+        crc.update(&bytes[..CHECKSUM_OFFSET]);
+        crc.update(&[0; 4]);
+        crc.update(&bytes[CHECKSUM_OFFSET+4..]);
+        if crc.finish() != checksum{
+            return Err(PacketError::BadChecksum);
+        }
+
+        let frames = reader.rest();
+        let mut reader = Reader::new(frames);
+        // at least one frame
+        PacketView::parse(&mut reader)?;
+        while !reader.is_empty(){
+            PacketView::parse(&mut reader)?;
+        }
+        Ok(DatagramView{connection_id, frames})
+    }
+
+    pub fn get_connection_id(&self) -> u32{
+        self.connection_id
+    }
+
+    /**
+     * Frames carried by the datagram, in the order they were written
+     */
+    pub fn frames(&self) -> Frames<'a>{
+        Frames{reader : Reader::new(self.frames)}
+    }
+}
+
+/**
+ * Iterator over the frames of a datagram
+ */
+pub struct Frames<'a>{
+    reader : Reader<'a>
+}
+
+impl<'a> Iterator for Frames<'a>{
+    type Item = PacketView<'a>;
+
+    fn next(&mut self) -> Option<PacketView<'a>>{
+        if self.reader.is_empty(){
+            return None;
+        }
+        // already validated when parsing the datagram
+        PacketView::parse(&mut self.reader).ok()
+    }
+}
+
+/**
+ * Frame parsed in place, borrowing its options and content from the receive buffer
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct PacketView<'a>{
+    flags : u8,
+    size : u64,
+    sequence : u64,
+    acked : u64,
+    options : &'a [u8],
+    content : &'a [u8]
+}
+
+impl<'a> PacketView<'a>{
+    fn parse(reader : &mut Reader<'a>) -> Result<PacketView<'a>, PacketError>{
+        let flags = reader.u8()?;
+        if flags & FLAG_RESERVED != 0{
+            return Err(PacketError::ReservedFlags(flags));
+        }
+        let compact = flags & FLAG_COMPACT != 0;
+        let (size, sequence, acked) = if compact{
+            let size = reader.varint()?;
+            let sequence = reader.varint()?;
+            let acked = if flags & FLAG_ACK != 0 { reader.varint()? } else { 0 };
+            (size, sequence, acked)
+        }else{
+            (reader.u64()?, reader.u64()?, reader.u64()?)
+        };
+        let options_len = match (flags & FLAG_OPTIONS != 0, compact){
+            (false, _) => 0,
+            (true, true) => reader.varint()?,
+            (true, false) => reader.u16()? as u64
+        };
+        let options = reader.bytes(usize::try_from(options_len).map_err(|_| PacketError::TruncatedHeader)?)?;
+        options::validate(options)?;
+        let remaining = reader.remaining() as u64;
+        if size > remaining{
+            return Err(PacketError::SizeMismatch{declared : size, actual : remaining});
+        }
+        let content = reader.bytes(size as usize)?;
+        Ok(PacketView{flags, size, sequence, acked, options, content})
+    }
+
+    /**
+     * Copy the view into an owned packet, for a connection to keep, its content and its options are copied
+     */
+    pub fn to_packet(&self) -> Packet{
+        Packet{size : self.size, content : self.content.to_vec(), sequence : self.sequence, acked : self.acked,
+            syn : self.is_syn(), ack : self.is_ack(), reset : self.is_reset(), fin : self.is_fin(), options : self.get_options()}
+    }
+
+    /**
+     * Options carried by the packet, unknown options are skipped.
+     * They are decoded into a new Vec, which only allocates when there are some
+     */
+    pub fn get_options(&self) -> Vec<PacketOption>{
+        if self.options.is_empty(){
+            return Vec::new();
+        }
+        // already validated when parsing
+        options::parse(self.options).unwrap_or_default()
+    }
+
+    pub fn get_content(&self) -> &'a [u8]{
+        self.content
+    }
+
+    pub fn get_sequence(&self) -> u64{
+        self.sequence
+    }
+
+    pub fn get_acked(&self) -> u64{
+        self.acked
+    }
+
+    pub fn get_size(&self) -> u64{
+        self.size
+    }
+
+    pub fn is_syn(&self) -> bool{
+        self.flags & FLAG_SYN != 0
+    }
+
+    pub fn is_ack(&self) -> bool{
+        self.flags & FLAG_ACK != 0
+    }
+
+    pub fn is_reset(&self) -> bool{
+        self.flags & FLAG_RESET != 0
+    }
+
+    pub fn is_fin(&self) -> bool{
+        self.flags & FLAG_FIN != 0
+    }
+}
+
+/**
+ * Reasons for rejecting a datagram
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError{
+    // the datagram is shorter than the header
+    TruncatedHeader,
+    // a varint field is longer than 64 bits
+    InvalidVarint,
+    // the datagram doesn't belong to this protocol
+    BadMagic(u16),
+    // the peer uses a version of the header we don't understand
+    UnsupportedVersion(u8),
+    // the datagram was corrupted on the way
+    BadChecksum,
+    // the size announced in the header doesn't match the content length
+    SizeMismatch{declared : u64, actual : u64},
+    // flags that aren't defined yet were set
+    ReservedFlags(u8),
+    // an option overflows the options section or has the wrong length for its kind
+    MalformedOption,
+    // the buffer given to the encoder can't hold the packet
+    BufferTooSmall
+}
+
+impl Display for PacketError{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self{
+            PacketError::TruncatedHeader => write!(f, "truncated header"),
+            PacketError::InvalidVarint => write!(f, "invalid varint"),
+            PacketError::BadMagic(magic) => write!(f, "bad magic {:#06x}", magic),
+            PacketError::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            PacketError::BadChecksum => write!(f, "bad checksum"),
+            PacketError::SizeMismatch{declared, actual} => write!(f, "declared size {} but content is {} bytes", declared, actual),
+            PacketError::ReservedFlags(flags) => write!(f, "reserved flags set in {:#04x}", flags),
+            PacketError::MalformedOption => write!(f, "malformed option"),
+            PacketError::BufferTooSmall => write!(f, "buffer too small")
+        }
+    }
+}
+
+impl std::error::Error for PacketError{}
+
+impl From<PacketError> for Error{
+    fn from(err : PacketError) -> Error{
+        Error::new(ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /**
+     * A datagram of connection 1 carrying the given frames, with a valid checksum
+     */
+    fn datagram(frames : &[u8]) -> Vec<u8>{
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC.to_be_bytes());
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        bytes.extend_from_slice(frames);
+        seal(bytes)
+    }
+
+    /**
+     * Compute the checksum again, so that only the tampered field gets the datagram rejected
+     */
+    fn seal(mut bytes : Vec<u8>) -> Vec<u8>{
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET+4].fill(0);
+        let checksum = crc32c(&bytes);
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET+4].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    fn encode(packet : &Packet, encoding : Encoding) -> Vec<u8>{
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let len = packet.encode_into(1, encoding, &mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn over_long_frame_sizes_are_rejected(){
+        let mut frame = vec![FLAG_COMPACT];
+        frame.extend_from_slice(&[0x80; 11]);
+        assert_eq!(DatagramView::parse(&datagram(&frame)).unwrap_err(), PacketError::InvalidVarint);
+    }
+
+    #[test]
+    fn packets_round_trip(){
+        let packet = Packet::new_data(b"hello".to_vec(), 1000, 42)
+            .with_option(PacketOption::Sack(vec![(1100, 1200), (1300, 1400)]))
+            .with_option(PacketOption::Timestamp{value : 7, echo : 3});
+        for encoding in [Encoding::Fixed, Encoding::Compact]{
+            let bytes = encode(&packet, encoding);
+            assert_eq!(bytes.len(), packet.encoded_len(encoding));
+            let datagram = DatagramView::parse(&bytes).unwrap();
+            assert_eq!(datagram.get_connection_id(), 1);
+            let frames : Vec<PacketView> = datagram.frames().collect();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].get_content(), b"hello");
+            assert_eq!(frames[0].get_sequence(), 1000);
+            assert_eq!(frames[0].get_acked(), 42);
+            assert_eq!(frames[0].get_options(), packet.get_options());
+        }
+    }
+
+    #[test]
+    fn truncated_datagrams_are_rejected(){
+        let packet = Packet::new_data(b"hello".to_vec(), 1000, 42).with_option(PacketOption::Mss(1460));
+        for encoding in [Encoding::Fixed, Encoding::Compact]{
+            let bytes = encode(&packet, encoding);
+            for len in 0..DATAGRAM_HEADER_SIZE{
+                assert_eq!(DatagramView::parse(&bytes[..len]).unwrap_err(), PacketError::TruncatedHeader);
+            }
+            for len in DATAGRAM_HEADER_SIZE..bytes.len(){
+                assert!(DatagramView::parse(&seal(bytes[..len].to_vec())).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_flags_are_rejected(){
+        let mut bytes = encode(&Packet::new_ack(1, 2), Encoding::Fixed);
+        bytes[DATAGRAM_HEADER_SIZE] |= 0x20;
+        assert_eq!(DatagramView::parse(&seal(bytes)).unwrap_err(), PacketError::ReservedFlags(0x21));
+    }
+
+    #[test]
+    fn size_mismatches_are_rejected(){
+        let bytes = encode(&Packet::new_data(vec![1; 10], 1, 2), Encoding::Compact);
+        let bytes = seal(bytes[..bytes.len()-3].to_vec());
+        assert_eq!(DatagramView::parse(&bytes).unwrap_err(), PacketError::SizeMismatch{declared : 10, actual : 7});
+    }
+}
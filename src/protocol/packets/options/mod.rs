@@ -0,0 +1,202 @@
+use super::PacketError;
+use super::codec::{Reader, Writer};
+
+/**
+ * Registry of the option kinds, each option is encoded as
+ * kind (1 byte), length of the value (1 byte), value.
+ * END and NOP are the exceptions, they are a single kind byte
+ */
+// end of the options, the remaining bytes of the section are padding
+const KIND_END : u8 = 0;
+// padding between options
+const KIND_NOP : u8 = 1;
+// maximum segment size the sender is willing to receive
+const KIND_MSS : u8 = 2;
+// shift applied to the window advertised by the sender
+const KIND_WINDOW_SCALE : u8 = 3;
+// ranges of sequence numbers received out of order
+const KIND_SACK : u8 = 5;
+// sender clock and echo of the last clock value received from the peer
+const KIND_TIMESTAMP : u8 = 8;
+// number of data segments received marked congestion experienced (ECN)
+const KIND_CE_COUNT : u8 = 9;
+// free space in the receive buffer of the sender, past the sequence number it acks
+const KIND_WINDOW : u8 = 10;
+
+// most SACK blocks that fit in a single option
+pub const MAX_SACK_BLOCKS : usize = 15;
+
+/**
+ * An option carried in the options section of a packet.
+ * Options of an unknown kind are skipped when parsing
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketOption{
+    Mss(u16),
+    WindowScale(u8),
+    // blocks of [start, end) sequence numbers
+    Sack(Vec<(u64, u64)>),
+    Timestamp{value : u32, echo : u32},
+    // wrapping count, repeated in every ack so that a lost ack loses nothing
+    CeCount(u32),
+    // in bytes, repeated in every ack so that the peer never sends more than we can hold
+    Window(u32)
+}
+
+impl PacketOption{
+    /**
+     * Number of bytes taken by the option once encoded
+     */
+    pub fn encoded_len(&self) -> usize{
+        match self{
+            PacketOption::Mss(_) => 4,
+            PacketOption::WindowScale(_) => 3,
+            PacketOption::Sack(blocks) => 2 + 16*usize::min(blocks.len(), MAX_SACK_BLOCKS),
+            PacketOption::Timestamp{..} => 10,
+            PacketOption::CeCount(_) => 6,
+            PacketOption::Window(_) => 6
+        }
+    }
+
+    /**
+     * Encode the option into the options section
+     */
+    pub fn write(&self, writer : &mut Writer) -> Result<(), PacketError>{
+        match self{
+            PacketOption::Mss(mss) => {
+                writer.bytes(&[KIND_MSS, 2])?;
+                writer.u16(*mss)
+            },
+            PacketOption::WindowScale(shift) => {
+                writer.bytes(&[KIND_WINDOW_SCALE, 1, *shift])
+            },
+            PacketOption::Sack(blocks) => {
+                // the length has to fit on a byte
+                let blocks = &blocks[..usize::min(blocks.len(), MAX_SACK_BLOCKS)];
+                writer.bytes(&[KIND_SACK, (16*blocks.len()) as u8])?;
+                for (start, end) in blocks{
+                    writer.u64(*start)?;
+                    writer.u64(*end)?;
+                }
+                Ok(())
+            },
+            PacketOption::Timestamp{value, echo} => {
+                writer.bytes(&[KIND_TIMESTAMP, 8])?;
+                writer.u32(*value)?;
+                writer.u32(*echo)
+            },
+            PacketOption::CeCount(count) => {
+                writer.bytes(&[KIND_CE_COUNT, 4])?;
+                writer.u32(*count)
+            },
+            PacketOption::Window(window) => {
+                writer.bytes(&[KIND_WINDOW, 4])?;
+                writer.u32(*window)
+            }
+        }
+    }
+}
+
+/**
+ * Every SACK block must cover some bytes, an inverted block would make the scoreboard panic
+ */
+fn valid_blocks(value : &[u8]) -> bool{
+    let mut reader = Reader::new(value);
+    while !reader.is_empty(){
+        match (reader.u64(), reader.u64()){
+            (Ok(start), Ok(end)) if start < end => {},
+            _ => return false
+        }
+    }
+    true
+}
+
+/**
+ * Walk an options section, calling f with the kind and value of every option
+ */
+fn walk<'a>(section : &'a [u8], mut f : impl FnMut(u8, &'a [u8]) -> Result<(), PacketError>) -> Result<(), PacketError>{
+    let mut reader = Reader::new(section);
+    while !reader.is_empty(){
+        let kind = reader.u8()?;
+        match kind{
+            KIND_END => break,
+            KIND_NOP => continue,
+            _ => {}
+        }
+        let len = reader.u8().map_err(|_| PacketError::MalformedOption)? as usize;
+        let value = reader.bytes(len).map_err(|_| PacketError::MalformedOption)?;
+        let valid = match kind{
+            KIND_MSS => len == 2,
+            KIND_WINDOW_SCALE => len == 1,
+            KIND_SACK => len.is_multiple_of(16) && valid_blocks(value),
+            KIND_TIMESTAMP => len == 8,
+            KIND_CE_COUNT => len == 4,
+            KIND_WINDOW => len == 4,
+            // unknown kind, its length tells us how much to skip
+            _ => true
+        };
+        if !valid{
+            return Err(PacketError::MalformedOption);
+        }
+        f(kind, value)?;
+    }
+    Ok(())
+}
+
+/**
+ * Check an options section without allocating anything
+ */
+pub fn validate(section : &[u8]) -> Result<(), PacketError>{
+    walk(section, |_, _| Ok(()))
+}
+
+/**
+ * Parse an options section, skipping the options we don't know about
+ */
+pub fn parse(section : &[u8]) -> Result<Vec<PacketOption>, PacketError>{
+    let mut options = Vec::new();
+    walk(section, |kind, value|{
+        let mut value = Reader::new(value);
+        match kind{
+            KIND_MSS => options.push(PacketOption::Mss(value.u16()?)),
+            KIND_WINDOW_SCALE => options.push(PacketOption::WindowScale(value.u8()?)),
+            KIND_SACK => {
+                let mut blocks = Vec::new();
+                while !value.is_empty(){
+                    blocks.push((value.u64()?, value.u64()?));
+                }
+                options.push(PacketOption::Sack(blocks));
+            },
+            KIND_TIMESTAMP => options.push(PacketOption::Timestamp{value : value.u32()?, echo : value.u32()?}),
+            KIND_CE_COUNT => options.push(PacketOption::CeCount(value.u32()?)),
+            KIND_WINDOW => options.push(PacketOption::Window(value.u32()?)),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn unknown_options_are_skipped(){
+        // an unknown kind 42 with a 3 bytes value, a nop, then an mss
+        let section = [42, 3, 0xaa, 0xbb, 0xcc, KIND_NOP, KIND_MSS, 2, 0x05, 0xb4];
+        assert_eq!(parse(&section), Ok(vec![PacketOption::Mss(1460)]));
+        // an unknown kind whose value overflows the section
+        assert_eq!(parse(&[42, 4, 0xaa]), Err(PacketError::MalformedOption));
+    }
+
+    #[test]
+    fn inverted_sack_blocks_are_rejected(){
+        for block in [(200, 100), (100, 100)]{
+            let mut section = [0; 34];
+            PacketOption::Sack(vec![(10, 20), block]).write(&mut Writer::new(&mut section)).unwrap();
+            assert_eq!(validate(&section), Err(PacketError::MalformedOption));
+            assert_eq!(parse(&section), Err(PacketError::MalformedOption));
+        }
+    }
+}
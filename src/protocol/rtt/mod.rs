@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+// timeout used until the first measure, as advised by RFC 6298
+pub const INITIAL_RTO : Duration = Duration::from_secs(1);
+// lower than the 1s advised by the RFC, so that recovery stays fast on short paths
+pub const MIN_RTO : Duration = Duration::from_millis(20);
+pub const MAX_RTO : Duration = Duration::from_secs(60);
+// granularity of the clock we measure with
+const CLOCK_GRANULARITY : Duration = Duration::from_millis(1);
+
+/**
+ * Estimation of the round trip time and of the retransmission timeout,
+ * following RFC 6298. Samples must only be taken from segments that were
+ * sent once (Karn's rule), the timeout doubles on each expiration until the next sample
+ */
+#[derive(Debug, Clone)]
+pub struct RttEstimator{
+    // smoothed round trip time, none until the first sample
+    srtt : Option<Duration>,
+    // round trip time variation
+    rttvar : Duration,
+    // current retransmission timeout
+    rto : Duration,
+    // lowest round trip time measured, none until the first sample
+    min_rtt : Option<Duration>
+}
+
+impl Default for RttEstimator{
+    fn default() -> Self{
+        RttEstimator::new()
+    }
+}
+
+impl RttEstimator{
+    pub fn new() -> RttEstimator{
+        RttEstimator{srtt : None, rttvar : Duration::ZERO, rto : INITIAL_RTO, min_rtt : None}
+    }
+
+    /**
+     * Update the estimation with a new round trip time measure
+     */
+    pub fn sample(&mut self, rtt : Duration){
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| Duration::min(min_rtt, rtt)));
+        let srtt = match self.srtt{
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            },
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                srtt * 7 / 8 + rtt / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + Duration::max(CLOCK_GRANULARITY, 4 * self.rttvar)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /**
+     * The retransmission timer expired, wait twice as long before the next one
+     */
+    pub fn backoff(&mut self){
+        self.rto = Duration::min(self.rto * 2, MAX_RTO);
+    }
+
+    pub fn rto(&self) -> Duration{
+        self.rto
+    }
+
+    pub fn get_srtt(&self) -> Option<Duration>{
+        self.srtt
+    }
+
+    pub fn get_min_rtt(&self) -> Option<Duration>{
+        self.min_rtt
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn ms(millis : u64) -> Duration{
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn the_first_sample_sets_the_estimation(){
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        rtt.sample(ms(100));
+        assert_eq!(rtt.get_srtt(), Some(ms(100)));
+        assert_eq!(rtt.rttvar, ms(50));
+        // srtt + 4 * rttvar
+        assert_eq!(rtt.rto(), ms(300));
+        assert_eq!(rtt.get_min_rtt(), Some(ms(100)));
+    }
+
+    #[test]
+    fn the_next_samples_are_smoothed(){
+        let mut rtt = RttEstimator::new();
+        rtt.sample(ms(100));
+        rtt.sample(ms(180));
+        // 3/4 of 50 + 1/4 of |100 - 180|, 7/8 of 100 + 1/8 of 180
+        assert_eq!(rtt.rttvar, ms(57) + Duration::from_micros(500));
+        assert_eq!(rtt.get_srtt(), Some(ms(110)));
+        assert_eq!(rtt.rto(), ms(340));
+        rtt.sample(ms(60));
+        assert_eq!(rtt.get_min_rtt(), Some(ms(60)));
+    }
+
+    #[test]
+    fn the_timeout_stays_within_bounds(){
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_micros(100));
+        assert_eq!(rtt.rto(), MIN_RTO);
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_secs(30));
+        assert_eq!(rtt.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn backoff_doubles_the_timeout_up_to_the_maximum(){
+        let mut rtt = RttEstimator::new();
+        rtt.sample(ms(100));
+        rtt.backoff();
+        assert_eq!(rtt.rto(), ms(600));
+        rtt.backoff();
+        assert_eq!(rtt.rto(), ms(1200));
+        for _ in 0..10{
+            rtt.backoff();
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+        // the next sample gives a fresh timeout
+        rtt.sample(ms(100));
+        assert!(rtt.rto() < MAX_RTO);
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::protocol::packets::PacketError;
+
+/**
+ * Counters shared by a protocol and all its connections
+ */
+#[derive(Debug, Default)]
+pub struct Stats{
+    // datagrams and bytes put on the wire, headers included
+    datagrams_sent : AtomicU64,
+    bytes_sent : AtomicU64,
+    // datagrams and bytes read from the wire, including the dropped ones
+    datagrams_received : AtomicU64,
+    bytes_received : AtomicU64,
+    // datagrams dropped because their checksum didn't match
+    checksum_failures : AtomicU64,
+    // datagrams dropped because they couldn't be parsed
+    malformed : AtomicU64,
+    // datagrams received marked congestion experienced by a router on the path
+    ce_marked : AtomicU64
+}
+
+impl Stats{
+    pub fn sent(&self, bytes : usize){
+        self.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes : usize){
+        self.datagrams_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn get_datagrams_sent(&self) -> u64{
+        self.datagrams_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn get_bytes_sent(&self) -> u64{
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn get_datagrams_received(&self) -> u64{
+        self.datagrams_received.load(Ordering::Relaxed)
+    }
+
+    pub fn get_bytes_received(&self) -> u64{
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn checksum_failed(&self){
+        self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_checksum_failures(&self) -> u64{
+        self.checksum_failures.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Record a datagram dropped by the decoder
+     */
+    pub fn dropped(&self, err : &PacketError){
+        match err{
+            PacketError::BadChecksum => self.checksum_failed(),
+            _ => {
+                self.malformed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn get_malformed(&self) -> u64{
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    pub fn ce_marked(&self){
+        self.ce_marked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_ce_marked(&self) -> u64{
+        self.ce_marked.load(Ordering::Relaxed)
+    }
+}
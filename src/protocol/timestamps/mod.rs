@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+use rand::random;
+
+use crate::protocol::packets::options::PacketOption;
+
+/**
+ * Clock of the timestamp option, in milliseconds so that PAWS stays valid
+ * for weeks, starting from a random value so that it tells nothing about the host
+ */
+#[derive(Debug, Clone)]
+pub struct TimestampClock{
+    origin : Instant,
+    offset : u32
+}
+
+impl Default for TimestampClock{
+    fn default() -> Self{
+        TimestampClock::new()
+    }
+}
+
+impl TimestampClock{
+    pub fn new() -> TimestampClock{
+        TimestampClock{origin : Instant::now(), offset : random()}
+    }
+
+    /**
+     * Current value of the clock, wrapping around
+     */
+    pub fn now(&self) -> u32{
+        self.offset.wrapping_add(self.origin.elapsed().as_millis() as u32)
+    }
+
+    /**
+     * Time elapsed since the clock gave the echoed value
+     */
+    pub fn since(&self, echo : u32) -> Duration{
+        Duration::from_millis(self.now().wrapping_sub(echo) as u64)
+    }
+}
+
+/**
+ * State of the timestamp option once negotiated during the handshake
+ */
+#[derive(Debug, Clone)]
+pub struct Timestamps{
+    // our clock, sent in every packet
+    pub clock : TimestampClock,
+    // latest timestamp received in order from the peer, echoed back
+    pub recent : u32
+}
+
+impl Timestamps{
+    pub fn new(clock : TimestampClock, recent : u32) -> Timestamps{
+        Timestamps{clock, recent}
+    }
+
+    /**
+     * The option to add to an outgoing packet
+     */
+    pub fn option(&self) -> PacketOption{
+        PacketOption::Timestamp{value : self.clock.now(), echo : self.recent}
+    }
+}
+
+/**
+ * Whether a timestamp was taken before another one, taking wrapping into account
+ */
+pub fn is_older(value : u32, than : u32) -> bool{
+    (value.wrapping_sub(than) as i32) < 0
+}
+
+/**
+ * Value and echo of the timestamp option among the options of a packet, if any
+ */
+pub fn timestamp_of(options : &[PacketOption]) -> Option<(u32, u32)>{
+    options.iter().find_map(|option| match option{
+        PacketOption::Timestamp{value, echo} => Some((*value, *echo)),
+        _ => None
+    })
+}
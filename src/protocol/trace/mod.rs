@@ -0,0 +1,227 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::protocol::packets::DatagramView;
+
+const SECTION_HEADER_BLOCK : u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK : u32 = 0x1;
+const ENHANCED_PACKET_BLOCK : u32 = 0x6;
+const BYTE_ORDER_MAGIC : u32 = 0x1A2B3C4D;
+// packets begin directly with an IPv4 or IPv6 header
+const LINKTYPE_RAW : u16 = 101;
+const OPT_END : u16 = 0;
+const OPT_COMMENT : u16 = 1;
+const IPPROTO_UDP : u8 = 17;
+const TTL : u8 = 64;
+
+/**
+ * Direction of a traced datagram, seen from this host
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction{
+    Sent,
+    Received
+}
+
+/**
+ * Writes every datagram going through a transport to a pcapng file,
+ * wrapped in synthetic IP/UDP headers so that standard tools can open it.
+ * The header fields of the frames are added as a comment on each packet
+ */
+#[derive(Debug)]
+pub struct Tracer{
+    out : Mutex<BufWriter<File>>
+}
+
+impl Tracer{
+    /**
+     * Create the trace file, overwriting any previous one
+     */
+    pub fn create<P : AsRef<Path>>(path : P) -> Result<Tracer, Error>{
+        let mut out = BufWriter::new(File::create(path)?);
+        write_block(&mut out, SECTION_HEADER_BLOCK, |body|{
+            body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+            // version 1.0
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            // unknown section length
+            body.extend_from_slice(&(-1i64).to_le_bytes());
+        })?;
+        write_block(&mut out, INTERFACE_DESCRIPTION_BLOCK, |body|{
+            body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            // no snapshot length limit
+            body.extend_from_slice(&0u32.to_le_bytes());
+        })?;
+        out.flush()?;
+        Ok(Tracer{out : Mutex::new(out)})
+    }
+
+    /**
+     * Record a datagram exchanged between local and peer,
+     * errors are ignored so that tracing never disturbs the connection
+     */
+    pub fn record(&self, direction : Direction, local : SocketAddr, peer : SocketAddr, datagram : &[u8]){
+        let (src, dst) = match direction{
+            Direction::Sent => (local, peer),
+            Direction::Received => (peer, local)
+        };
+        let packet = ip_udp_packet(src, dst, datagram);
+        let comment = describe(direction, datagram);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        let mut out = self.out.lock().unwrap();
+        let _ = write_block(&mut *out, ENHANCED_PACKET_BLOCK, |body|{
+            // interface 0, timestamps in microseconds
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&packet);
+            pad(body);
+            body.extend_from_slice(&OPT_COMMENT.to_le_bytes());
+            body.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+            body.extend_from_slice(comment.as_bytes());
+            pad(body);
+            body.extend_from_slice(&OPT_END.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+        }).and_then(|_| out.flush());
+    }
+}
+
+/**
+ * Write a block, framed by its type and its total length repeated at both ends
+ */
+fn write_block<W : Write, F : FnOnce(&mut Vec<u8>)>(out : &mut W, block_type : u32, fill : F) -> Result<(), Error>{
+    let mut body = Vec::new();
+    fill(&mut body);
+    let total = (body.len() + 12) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(&body)?;
+    out.write_all(&total.to_le_bytes())
+}
+
+/**
+ * Blocks and options are aligned on 32 bits
+ */
+fn pad(body : &mut Vec<u8>){
+    while !body.len().is_multiple_of(4){
+        body.push(0);
+    }
+}
+
+/**
+ * One's complement sum used by the IP and UDP checksums
+ */
+fn sum(mut acc : u32, bytes : &[u8]) -> u32{
+    for chunk in bytes.chunks(2){
+        let word = if chunk.len() == 2{ u16::from_be_bytes([chunk[0], chunk[1]]) } else { u16::from_be_bytes([chunk[0], 0]) };
+        acc += word as u32;
+    }
+    acc
+}
+
+fn fold(mut acc : u32) -> u16{
+    while acc > 0xffff{
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+/**
+ * Wrap a datagram in IP and UDP headers, both ends are mapped
+ * to IPv6 as soon as one of them isn't an IPv4 address
+ */
+fn ip_udp_packet(src : SocketAddr, dst : SocketAddr, datagram : &[u8]) -> Vec<u8>{
+    let udp_len = (8 + datagram.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&0u16.to_be_bytes());
+    udp.extend_from_slice(datagram);
+
+    let mut packet = Vec::with_capacity(40 + udp.len());
+    let pseudo = match (src.ip(), dst.ip()){
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            // id, then don't fragment
+            packet.extend_from_slice(&[0, 0, 0x40, 0, TTL, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = fold(sum(0, &packet));
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo.extend_from_slice(&udp_len.to_be_bytes());
+            pseudo
+        },
+        (src, dst) => {
+            let to_v6 = |ip : IpAddr| match ip{
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip
+            };
+            let (src, dst) = (to_v6(src), to_v6(dst));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[IPPROTO_UDP, TTL]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            pseudo
+        }
+    };
+    let checksum = match fold(sum(sum(0, &pseudo), &udp)){
+        // a zero checksum means no checksum at all
+        0 => 0xffff,
+        checksum => checksum
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+/**
+ * Human readable header fields of every frame in the datagram,
+ * or the reason why it was dropped
+ */
+fn describe(direction : Direction, datagram : &[u8]) -> String{
+    let mut comment = String::from(match direction{
+        Direction::Sent => "sent",
+        Direction::Received => "received"
+    });
+    let view = match DatagramView::parse(datagram){
+        Ok(view) => view,
+        Err(err) => {
+            let _ = write!(comment, ", dropped: {}", err);
+            return comment;
+        }
+    };
+    let _ = write!(comment, " conn={:#010x}", view.get_connection_id());
+    for frame in view.frames(){
+        let mut flags = Vec::new();
+        if frame.is_syn(){ flags.push("syn"); }
+        if frame.is_ack(){ flags.push("ack"); }
+        if frame.is_fin(){ flags.push("fin"); }
+        if frame.is_reset(){ flags.push("rst"); }
+        let _ = write!(comment, " | [{}] seq={} ack={} len={}", flags.join(","), frame.get_sequence(), frame.get_acked(), frame.get_size());
+        let options = frame.get_options();
+        if !options.is_empty(){
+            let _ = write!(comment, " options={:?}", options);
+        }
+    }
+    comment
+}
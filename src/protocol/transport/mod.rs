@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::ecn::{self, Ecn};
+use crate::protocol::packets::{encode_datagram, version_reply, DatagramView, Encoding, Packet, PacketError, MAX_DATAGRAM_SIZE, VERSION_REPLY_SIZE};
+use crate::protocol::stats::Stats;
+use crate::protocol::trace::{Direction, Tracer};
+
+thread_local!{
+    // encoding buffer reused by every datagram sent from this thread
+    static SEND_BUFFER : RefCell<Vec<u8>> = RefCell::new(vec![0; MAX_DATAGRAM_SIZE]);
+}
+
+/**
+ * Wrapper around the UDP socket, all datagrams go through it so that
+ * they are encoded and accounted for in the same way
+ */
+#[derive(Debug)]
+pub struct Transport{
+    socket : Arc<UdpSocket>,
+    stats : Arc<Stats>,
+    encoding : Encoding,
+    // records the datagrams to a pcapng file when enabled
+    tracer : Option<Tracer>,
+    // send the datagrams carrying data ECN capable, acks and handshakes never are (RFC 3168)
+    ecn : bool,
+    // peers that sent us a version reply, along with the version they run
+    versions : Mutex<HashMap<SocketAddr, u8>>
+}
+
+impl Transport{
+    pub fn new(socket : Arc<UdpSocket>, stats : Arc<Stats>, encoding : Encoding, tracer : Option<Tracer>, ecn : bool) -> Transport{
+        Transport{socket, stats, encoding, tracer, ecn, versions : Mutex::new(HashMap::new())}
+    }
+
+    pub fn encoding(&self) -> Encoding{
+        self.encoding
+    }
+
+    /**
+     * Encode a packet of the connection with the given id and send it to addr
+     */
+    pub fn send_to(&self, packet : &Packet, connection_id : u32, addr : &str) -> Result<(), Error>{
+        self.send_frames(std::slice::from_ref(packet), connection_id, addr)
+    }
+
+    /**
+     * Encode packets of the connection with the given id as the frames
+     * of a single datagram, and send it to addr
+     */
+    pub fn send_frames(&self, frames : &[Packet], connection_id : u32, addr : &str) -> Result<(), Error>{
+        SEND_BUFFER.with(|buf|{
+            let mut buf = buf.borrow_mut();
+            let len = encode_datagram(frames, connection_id, self.encoding, &mut buf)?;
+            if self.ecn && frames.iter().any(|frame| frame.get_size() > 0){
+                let addr = addr.to_socket_addrs()?.next().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No address"))?;
+                ecn::send_to(&self.socket, &buf[..len], addr, Ecn::Ect0)?;
+            }else{
+                self.socket.send_to(&buf[..len], addr)?;
+            }
+            self.stats.sent(len);
+            if let Some(tracer) = &self.tracer{
+                if let (Ok(local), Some(peer)) = (self.socket.local_addr(), addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())){
+                    tracer.record(Direction::Sent, local, peer, &buf[..len]);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /**
+     * Wait for the next datagram and parse it in place, along with its ECN codepoint,
+     * return Ok(None) if the datagram had to be dropped
+     */
+    pub fn recv_from<'a>(&self, buf : &'a mut [u8]) -> Result<Option<(DatagramView<'a>, SocketAddr, Ecn)>, Error>{
+        let (amt, src, ecn) = ecn::recv_from(&self.socket, buf)?;
+        self.stats.received(amt);
+        if ecn == Ecn::Ce{
+            self.stats.ce_marked();
+        }
+        if let (Some(tracer), Ok(local)) = (&self.tracer, self.socket.local_addr()){
+            // traced before parsing, so that dropped datagrams show up too
+            tracer.record(Direction::Received, local, src, &buf[..amt]);
+        }
+        match DatagramView::parse(&buf[..amt]){
+            Ok(packet) => Ok(Some((packet, src, ecn))),
+            Err(err) => {
+                self.stats.dropped(&err);
+                if let PacketError::UnsupportedVersion(version) = err{
+                    self.version_mismatch(amt, src, version);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /**
+     * A datagram of another version came, answer with ours unless it is already
+     * the answer of the peer, which is kept to tell why the peer doesn't answer
+     */
+    fn version_mismatch(&self, amt : usize, src : SocketAddr, version : u8){
+        if amt == VERSION_REPLY_SIZE{
+            self.versions.lock().unwrap().insert(src, version);
+            return;
+        }
+        if self.socket.send_to(&version_reply(), src).is_ok(){
+            self.stats.sent(VERSION_REPLY_SIZE);
+        }
+    }
+
+    /**
+     * Version run by the peer at addr, if it told us it doesn't run ours
+     */
+    pub fn peer_version(&self, addr : &str) -> Option<u8>{
+        let addr = addr.to_socket_addrs().ok()?.next()?;
+        self.versions.lock().unwrap().get(&addr).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Duration;
+
+    use super::*;
+    use crate::protocol::packets::{MAGIC, VERSION};
+
+    fn socket() -> Arc<UdpSocket>{
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        socket
+    }
+
+    #[test]
+    fn peers_on_another_version_get_a_version_reply(){
+        let peer = socket();
+        let socket = socket();
+        let transport = Transport::new(socket.clone(), Arc::new(Stats::default()), Encoding::default(), None, false);
+        let addr = socket.local_addr().unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+        // a syn from the next version
+        let len = encode_datagram(&[Packet::new_syn(1)], 1, Encoding::default(), &mut buf).unwrap();
+        buf[2] = VERSION + 1;
+        peer.send_to(&buf[..len], addr).unwrap();
+        assert!(transport.recv_from(&mut buf).unwrap().is_none());
+        let (amt, _) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], &version_reply());
+        assert_eq!(transport.peer_version(&peer.local_addr().unwrap().to_string()), None);
+
+        // its own version reply is kept, and not answered
+        let magic = MAGIC.to_be_bytes();
+        peer.send_to(&[magic[0], magic[1], VERSION + 1], addr).unwrap();
+        assert!(transport.recv_from(&mut buf).unwrap().is_none());
+        assert_eq!(transport.peer_version(&peer.local_addr().unwrap().to_string()), Some(VERSION + 1));
+        assert!(peer.recv_from(&mut buf).is_err());
+    }
+}
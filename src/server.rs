@@ -1,8 +1,11 @@
 use clap::Parser;
-use std::{sync::{Arc, Mutex}, thread::{self, sleep}, time::Duration};
+use std::{path::PathBuf, sync::{Arc, Mutex}, thread::{self, sleep}, time::Duration};
 
 pub mod protocol;
 use protocol::Protocol;
+use protocol::config::Config;
+use protocol::packets::Encoding;
+use protocol::congestion::Algorithm;
 
 /// Server for custom protocol
 #[derive(Parser, Debug)]
@@ -11,6 +14,26 @@ struct Args {
     /// Address used to bind the server
     #[arg(short, long)]
     addr: String,
+
+    /// Use the fixed size header encoding, easier to read when debugging
+    #[arg(long)]
+    fixed_header : bool,
+
+    /// Record the traffic to a pcapng file, to be opened with standard tools
+    #[arg(long)]
+    trace : Option<PathBuf>,
+
+    /// Congestion control algorithm, newreno, cubic or bbr
+    #[arg(long, default_value = "newreno")]
+    congestion : Algorithm,
+
+    /// Send at most this many bytes per second, paced from the congestion control otherwise
+    #[arg(long)]
+    pacing_rate : Option<u64>,
+
+    /// Bytes each connection holds for the application before the client must wait
+    #[arg(long)]
+    receive_buffer : Option<u64>
 }
 
 fn main() -> Result<(), std::io::Error>{
@@ -20,7 +43,17 @@ fn main() -> Result<(), std::io::Error>{
 
     println!("Hello from server");
 
-    let mut server = Protocol::new(&addr)?;
+    let mut config = Config::default();
+    if args.fixed_header{
+        config.encoding = Encoding::Fixed;
+    }
+    config.trace = args.trace;
+    config.congestion = args.congestion;
+    config.pacing_rate = args.pacing_rate;
+    if let Some(receive_buffer) = args.receive_buffer{
+        config.receive_buffer = receive_buffer;
+    }
+    let mut server = Protocol::with_config(&addr, config)?;
     let number_syn = Arc::new(Mutex::new(0));
     let failing_syns = Arc::new(vec![0, 4, 10]);
     loop{